@@ -0,0 +1,326 @@
+use crate::error::{Error, Result};
+use crate::rules::Rule;
+use crate::template::Template;
+use crate::{xdg, Config};
+use clap::parser::ValueSource;
+use clap::{ArgMatches, CommandFactory, FromArgMatches};
+use log::LevelFilter;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::fs;
+use std::io::ErrorKind;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use toml::Spanned;
+
+/// Default expiration timeouts in the `[timeouts]` table.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TimeoutsSection {
+    /// timeout of low urgency notifications in milliseconds
+    pub low: Option<u32>,
+    /// timeout of normal urgency notifications in milliseconds
+    pub normal: Option<u32>,
+    /// timeout of critical notifications in milliseconds
+    pub critical: Option<u32>,
+    /// whether critical notifications expire
+    pub expire_critical: Option<bool>,
+}
+
+/// Content of the configuration file, every key is optional.
+///
+/// Keys are named like the command line options, with underscores, e.g.
+///
+/// ```toml
+/// socket_path = "/run/user/1000/rofi_notification_daemon"
+/// dbus_poll_timeout = 500
+/// history_size = 50
+/// log_level = "info"
+/// template = "{time} {app}: {summary}"
+///
+/// [timeouts]
+/// low = 5000
+/// normal = 10000
+/// critical = 0
+///
+/// [templates]
+/// short = "{id} {summary}"
+///
+/// [[rules]]
+/// app_name = "^spotify$"
+/// set_urgency = "low"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    /// see [`Config::log_level`]
+    pub log_level: Option<Spanned<String>>,
+    /// see [`Config::socket_path`]
+    pub socket_path: Option<PathBuf>,
+    /// see [`Config::dbus_poll_timeout`]
+    pub dbus_poll_timeout: Option<Spanned<u16>>,
+    /// see [`TimeoutsSection`]
+    pub timeouts: Option<TimeoutsSection>,
+    /// see [`Config::move_replaced`]
+    pub move_replaced: Option<bool>,
+    /// see [`Config::image_cache_size`]
+    pub image_cache_size: Option<u64>,
+    /// see [`Config::icon_theme`]
+    pub icon_theme: Option<Spanned<String>>,
+    /// see [`Config::icon_size`]
+    pub icon_size: Option<Spanned<u32>>,
+    /// see [`Config::link_opener`]
+    pub link_opener: Option<Spanned<String>>,
+    /// see [`Config::history_size`]
+    pub history_size: Option<usize>,
+    /// see [`Config::socket_timeout`]
+    pub socket_timeout: Option<Spanned<u64>>,
+    /// see [`Config::legacy_protocol`]
+    pub legacy_protocol: Option<bool>,
+    /// see [`Config::state_file`]
+    pub state_file: Option<PathBuf>,
+    /// see [`Config::queue_critical`]
+    pub queue_critical: Option<bool>,
+    /// see [`Config::template`]
+    pub template: Option<Spanned<String>>,
+    /// see [`Config::templates`]
+    #[serde(default)]
+    pub templates: BTreeMap<String, Spanned<String>>,
+    /// see [`Rule`]
+    #[serde(default)]
+    pub rules: Vec<Rule>,
+}
+
+/// Problem found in a configuration file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+    /// line of the problem, starting at 1
+    pub line: usize,
+    /// column of the problem, starting at 1
+    pub column: usize,
+    /// description of the problem
+    pub message: String,
+}
+
+impl Diagnostic {
+    /// Locates the problem at the start of a span of the source.
+    fn new(source: &str, span: Option<Range<usize>>, message: impl Into<String>) -> Self {
+        let offset = span.map_or(0, |span| span.start).min(source.len());
+        let before = &source[..offset];
+        Self {
+            line: before.matches('\n').count() + 1,
+            column: before.len() - before.rfind('\n').map_or(0, |index| index + 1) + 1,
+            message: message.into(),
+        }
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl ConfigFile {
+    /// Returns the default path of the configuration file, in `$XDG_CONFIG_HOME`.
+    pub fn default_path() -> PathBuf {
+        xdg::config_home().join("armesto").join("config.toml")
+    }
+
+    /// Parses and validates a configuration file.
+    pub fn parse(source: &str) -> std::result::Result<Self, Vec<Diagnostic>> {
+        let file: Self = toml::from_str(source)
+            .map_err(|e| vec![Diagnostic::new(source, e.span(), e.message())])?;
+
+        let mut diagnostics = Vec::new();
+        let mut check = |valid: bool, span: Range<usize>, message: &str| {
+            if !valid {
+                diagnostics.push(Diagnostic::new(source, Some(span), message));
+            }
+        };
+        if let Some(level) = &file.log_level {
+            check(
+                level.get_ref().parse::<LevelFilter>().is_ok(),
+                level.span(),
+                "log_level must be one of off, error, warn, info, debug or trace",
+            );
+        }
+        if let Some(timeout) = &file.dbus_poll_timeout {
+            check(*timeout.get_ref() > 0, timeout.span(), "dbus_poll_timeout must be positive");
+        }
+        if let Some(timeout) = &file.socket_timeout {
+            check(*timeout.get_ref() > 0, timeout.span(), "socket_timeout must be positive");
+        }
+        if let Some(size) = &file.icon_size {
+            check(*size.get_ref() > 0, size.span(), "icon_size must be positive");
+        }
+        if let Some(theme) = &file.icon_theme {
+            check(!theme.get_ref().trim().is_empty(), theme.span(), "icon_theme must not be empty");
+        }
+        if let Some(opener) = &file.link_opener {
+            check(!opener.get_ref().trim().is_empty(), opener.span(), "link_opener must not be empty");
+        }
+        for template in file.template.iter().chain(file.templates.values()) {
+            if let Err(e) = Template::parse(template.get_ref()) {
+                check(false, template.span(), &format!("invalid template: {e}"));
+            }
+        }
+
+        if diagnostics.is_empty() {
+            Ok(file)
+        } else {
+            Err(diagnostics)
+        }
+    }
+
+    /// Reads a configuration file.
+    ///
+    /// Returns `None` if the file does not exist and is not `required`.
+    pub fn read(path: &Path, required: bool) -> Result<Option<Self>> {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) if e.kind() == ErrorKind::NotFound && !required => return Ok(None),
+            Err(e) => return Err(Error::Config(format!("{}: {}", path.display(), e))),
+        };
+
+        Self::parse(&source).map(Some).map_err(|diagnostics| {
+            Error::Config(
+                diagnostics
+                    .iter()
+                    .map(|diagnostic| format!("{}: {}", path.display(), diagnostic))
+                    .collect::<Vec<String>>()
+                    .join("\n"),
+            )
+        })
+    }
+}
+
+impl Config {
+    /// Reads the configuration from the command line, the environment and the configuration file.
+    ///
+    /// Exits on invalid command lines or `--help`, like [`clap::Parser::parse`].
+    /// The configuration file is not read for tasks, which handle it themselves.
+    pub fn load() -> Result<Self> {
+        let matches = Self::command().get_matches();
+        let mut config = Self::from_arg_matches(&matches).map_err(|e| Error::Config(e.to_string()))?;
+        if config.task.is_none() {
+            if let Some(file) = ConfigFile::read(&config.config_path(), config.config.is_some())? {
+                config.merge(file, &matches);
+            }
+        }
+        Ok(config)
+    }
+
+    /// Path of the configuration file
+    pub fn config_path(&self) -> PathBuf {
+        self.config.clone().unwrap_or_else(ConfigFile::default_path)
+    }
+
+    /// Takes the values of the file for the options not set in the environment or on the command line.
+    fn merge(&mut self, file: ConfigFile, matches: &ArgMatches) {
+        let unset = |id: &str| matches!(matches.value_source(id), None | Some(ValueSource::DefaultValue));
+        macro_rules! layer {
+            ($id:ident, $value:expr) => {
+                if let Some(value) = $value {
+                    if unset(stringify!($id)) {
+                        self.$id = value;
+                    }
+                }
+            };
+        }
+
+        let timeouts = file.timeouts.unwrap_or_default();
+        layer!(log_level, file.log_level.and_then(|level| level.into_inner().parse().ok()));
+        layer!(socket_path, file.socket_path.map(Some));
+        layer!(dbus_poll_timeout, file.dbus_poll_timeout.map(Spanned::into_inner));
+        layer!(low_timeout, timeouts.low);
+        layer!(normal_timeout, timeouts.normal);
+        layer!(critical_timeout, timeouts.critical);
+        layer!(expire_critical, timeouts.expire_critical);
+        layer!(move_replaced, file.move_replaced);
+        layer!(image_cache_size, file.image_cache_size);
+        layer!(icon_theme, file.icon_theme.map(Spanned::into_inner));
+        layer!(icon_size, file.icon_size.map(Spanned::into_inner));
+        layer!(link_opener, file.link_opener.map(Spanned::into_inner));
+        layer!(history_size, file.history_size);
+        layer!(socket_timeout, file.socket_timeout.map(Spanned::into_inner));
+        layer!(legacy_protocol, file.legacy_protocol);
+        layer!(state_file, file.state_file.map(Some));
+        layer!(queue_critical, file.queue_critical);
+        layer!(template, file.template.map(Spanned::into_inner));
+        self.templates = file
+            .templates
+            .into_iter()
+            .map(|(name, template)| (name, template.into_inner()))
+            .collect();
+        self.rules = file.rules;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_file_parse() {
+        let file = ConfigFile::parse(
+            "socket_path = \"/run/armesto.sock\"\nhistory_size = 5\nlog_level = \"info\"\n\n[timeouts]\nlow = 1000\n",
+        )
+        .expect("Parses file");
+
+        assert_eq!(file.socket_path, Some(PathBuf::from("/run/armesto.sock")));
+        assert_eq!(file.history_size, Some(5));
+        assert_eq!(file.timeouts.and_then(|t| t.low), Some(1000));
+    }
+
+    #[test]
+    fn config_file_diagnostics() {
+        let line = |source: &str| {
+            ConfigFile::parse(source)
+                .expect_err("Rejects file")
+                .iter()
+                .map(|d| (d.line, d.column))
+                .collect::<Vec<(usize, usize)>>()
+        };
+
+        assert_eq!(line("history_size = 5\nhistory_size = \"x\"\n"), vec![(2, 1)], "duplicate key");
+        assert_eq!(line("history_size = 5\n\nunknown = 1\n"), vec![(3, 1)], "unknown key");
+        assert_eq!(line("[timeouts]\nlow = -1\n"), vec![(2, 7)], "invalid type");
+        assert_eq!(
+            line("[[rules]]\nname = \"x\"\n\n[[rules]]\nsummary = \"[a-\"\n"),
+            vec![(5, 11)],
+            "invalid pattern"
+        );
+        assert_eq!(
+            line("template = \"{app\"\n\n[templates]\nshort = \"{sender}\"\n"),
+            vec![(1, 12), (4, 9)],
+            "invalid templates"
+        );
+        assert_eq!(
+            line("log_level = \"loud\"\nicon_size = 0\n"),
+            vec![(1, 13), (2, 13)],
+            "validation errors"
+        );
+    }
+
+    #[test]
+    fn config_layers() {
+        let file = || {
+            ConfigFile::parse("history_size = 5\nicon_theme = \"Adwaita\"\nlink_opener = \"firefox\"\n")
+                .expect("Parses file")
+        };
+        let load = |args: &[&str]| {
+            let matches = Config::command().get_matches_from(args);
+            let mut config = Config::from_arg_matches(&matches).expect("Parses arguments");
+            config.merge(file(), &matches);
+            config
+        };
+
+        let config = load(&["armesto", "--icon-theme", "Papirus"]);
+        assert_eq!(config.history_size, 5, "file overrides default");
+        assert_eq!(config.icon_theme, "Papirus", "command line overrides file");
+        assert_eq!(config.link_opener, "firefox");
+        assert_eq!(config.icon_size, 32, "default is kept");
+    }
+}
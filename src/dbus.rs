@@ -1,16 +1,23 @@
 use crate::error::{self, Error};
-use crate::notification::{Notification, Action};
-use dbus::arg::{RefArg, Variant};
-use dbus::blocking::stdintf::org_freedesktop_dbus::RequestNameReply;
+use crate::hint::Hint;
+use crate::image::ImageData;
+use crate::notification::{Action, CloseReason, Notification, NotificationStore, Urgency};
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::blocking::stdintf::org_freedesktop_dbus::{PropertiesPropertiesChanged, RequestNameReply};
 use dbus::blocking::{Connection, Proxy};
-use dbus::channel::MatchingReceiver;
-use dbus::message::MatchRule;
-use dbus::MethodErr;
+use dbus::channel::{BusType, Channel, MatchingReceiver};
+use dbus::message::{MatchRule, SignalArgs};
+use dbus::{Message, MethodErr};
 use dbus_crossroads::Crossroads;
-use log::{trace, debug};
+use log::{debug, warn};
 use std::collections::HashMap;
+use std::io::{self, ErrorKind, Read, Write};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::path::PathBuf;
 use std::sync::atomic::{AtomicU32, Ordering};
-use std::sync::mpsc::Sender;
+use std::sync::mpsc::{self, Receiver, SendError, Sender};
+use std::sync::Arc;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 /// D-Bus server information.
@@ -27,22 +34,238 @@ const SERVER_INFO: [&str; 4] = [
 ///
 /// - `actions`: The server will provide the specified actions to the user.
 /// - `body`: Supports body text.
-const SERVER_CAPABILITIES: [&str; 2] = ["actions", "body"];
+/// - `body-hyperlinks`: The server supports hyperlinks in the notifications.
+/// - `body-markup`: Supports markup in the body text.
+const SERVER_CAPABILITIES: [&str; 4] = ["actions", "body", "body-hyperlinks", "body-markup"];
 
 mod dbus_server {
-    #![allow(clippy::too_many_arguments)]
+    #![allow(dead_code, clippy::too_many_arguments)]
     include!(concat!(env!("OUT_DIR"), "/introspection.rs"));
 }
 
 /// ID counter for the notification.
 static ID_COUNT: AtomicU32 = AtomicU32::new(1);
 
+/// Returns the id the next notification will get.
+pub fn next_id() -> u32 {
+    ID_COUNT.load(Ordering::Relaxed)
+}
+
+/// Returns a new id for a notification.
+pub fn issue_id() -> u32 {
+    ID_COUNT.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Continues the ids of the notifications from the given id, e.g. after a restart.
+pub fn set_next_id(id: u32) {
+    ID_COUNT.store(id.max(1), Ordering::Relaxed);
+}
+
 /// D-Bus interface for desktop notifications.
 const NOTIFICATION_INTERFACE: &str = "org.freedesktop.Notifications";
 
 /// D-Bus path for desktop notifications.
 const NOTIFICATION_PATH: &str = "/org/freedesktop/Notifications";
 
+/// D-Bus interface for controlling the daemon.
+const CONTROL_INTERFACE: &str = "org.armesto.Control1";
+
+/// D-Bus path for controlling the daemon.
+const CONTROL_PATH: &str = "/org/freedesktop/Notifications/ctl";
+
+/// Signals emitted by the notification server.
+#[derive(Debug)]
+pub enum Signal {
+    /// A notification was closed, see `org.freedesktop.Notifications.NotificationClosed`
+    NotificationClosed(u32, CloseReason),
+    /// An action was invoked, see `org.freedesktop.Notifications.ActionInvoked`
+    ActionInvoked(u32, String),
+    /// A notification was delivered, see `org.armesto.Control1.NotificationAdded`
+    NotificationAdded(u32),
+    /// A notification was replaced, see `org.armesto.Control1.NotificationReplaced`
+    NotificationReplaced(u32),
+    /// A notification was removed, see `org.armesto.Control1.NotificationRemoved`
+    NotificationRemoved(u32, CloseReason),
+    /// The state of a notification changed, see `org.armesto.Control1.NotificationStateChanged`
+    NotificationStateChanged {
+        /// notification id
+        id: u32,
+        /// whether the user has seen the notification
+        seen: bool,
+        /// urgency of the notification
+        urgency: Urgency,
+    },
+}
+
+/// Creates the queue of the signals to emit, waking the D-Bus thread up whenever one is queued.
+pub fn signal_channel() -> io::Result<(SignalSender, SignalReceiver)> {
+    let (waker, wakeup) = UnixStream::pair()?;
+    waker.set_nonblocking(true)?;
+    wakeup.set_nonblocking(true)?;
+    let (sender, receiver) = mpsc::channel();
+    Ok((
+        SignalSender {
+            sender,
+            waker: Arc::new(waker),
+        },
+        SignalReceiver { receiver, wakeup },
+    ))
+}
+
+/// Sending end of the queue of signals, see [`signal_channel`].
+#[derive(Clone, Debug)]
+pub struct SignalSender {
+    sender: Sender<Signal>,
+    waker: Arc<UnixStream>,
+}
+
+impl SignalSender {
+    /// Queues a signal and wakes the D-Bus thread up to emit it.
+    pub fn send(&self, signal: Signal) -> Result<(), SendError<Signal>> {
+        self.sender.send(signal)?;
+        self.wake();
+        Ok(())
+    }
+
+    /// Wakes the D-Bus thread up to emit the queued signals and the changed properties.
+    pub fn wake(&self) {
+        // A full socket already holds a pending wake-up.
+        let _ = (&*self.waker).write(&[0]);
+    }
+}
+
+/// Receiving end of the queue of signals, see [`signal_channel`].
+#[derive(Debug)]
+pub struct SignalReceiver {
+    receiver: Receiver<Signal>,
+    wakeup: UnixStream,
+}
+
+impl SignalReceiver {
+    /// Waits until the connection is readable, or writable if `write` is set, the thread
+    /// is woken up, or the timeout elapses.
+    fn wait(&self, connection: RawFd, write: bool, timeout: Duration) -> io::Result<()> {
+        let mut events = libc::POLLIN;
+        if write {
+            events |= libc::POLLOUT;
+        }
+        let mut fds = [
+            libc::pollfd {
+                fd: connection,
+                events,
+                revents: 0,
+            },
+            libc::pollfd {
+                fd: self.wakeup.as_raw_fd(),
+                events: libc::POLLIN,
+                revents: 0,
+            },
+        ];
+        let timeout = libc::c_int::try_from(timeout.as_millis()).unwrap_or(libc::c_int::MAX);
+        // SAFETY: the file descriptors are open and the array outlives the call.
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+            let e = io::Error::last_os_error();
+            if e.kind() != ErrorKind::Interrupted {
+                return Err(e);
+            }
+        }
+        let mut discarded = [0; 64];
+        loop {
+            match (&self.wakeup).read(&mut discarded) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+impl Signal {
+    /// Converts the signal into a D-Bus message.
+    fn into_message(self) -> Message {
+        let path = dbus::Path::from(NOTIFICATION_PATH);
+        let control_path = dbus::Path::from(CONTROL_PATH);
+        match self {
+            Signal::NotificationClosed(id, reason) => {
+                dbus_server::OrgFreedesktopNotificationsNotificationClosed {
+                    id,
+                    reason: reason as u32,
+                }
+                .to_emit_message(&path)
+            }
+            Signal::ActionInvoked(id, action_key) => {
+                dbus_server::OrgFreedesktopNotificationsActionInvoked { id, action_key }
+                    .to_emit_message(&path)
+            }
+            Signal::NotificationAdded(id) => {
+                dbus_server::OrgArmestoControl1NotificationAdded { id }
+                    .to_emit_message(&control_path)
+            }
+            Signal::NotificationReplaced(id) => {
+                dbus_server::OrgArmestoControl1NotificationReplaced { id }
+                    .to_emit_message(&control_path)
+            }
+            Signal::NotificationRemoved(id, reason) => {
+                dbus_server::OrgArmestoControl1NotificationRemoved {
+                    id,
+                    reason: reason as u32,
+                }
+                .to_emit_message(&control_path)
+            }
+            Signal::NotificationStateChanged { id, seen, urgency } => {
+                let mut state = PropMap::new();
+                state.insert("seen".to_string(), Variant(Box::new(seen)));
+                state.insert("urgency".to_string(), Variant(Box::new(urgency as u8)));
+                dbus_server::OrgArmestoControl1NotificationStateChanged { id, state }
+                    .to_emit_message(&control_path)
+            }
+        }
+    }
+}
+
+/// Daemon state exposed as properties of the ctl object.
+#[derive(Clone, Debug, Default, PartialEq)]
+struct ControlProperties {
+    count: u32,
+    unseen_count: u32,
+    critical_count: u32,
+    paused: bool,
+}
+
+impl ControlProperties {
+    /// Reads the current state from the store.
+    fn read(db: &NotificationStore) -> Self {
+        Self {
+            count: db.count() as u32,
+            unseen_count: db.unseen_count() as u32,
+            critical_count: db.critical_count() as u32,
+            paused: db.is_paused(),
+        }
+    }
+
+    /// Returns the properties that differ from the previous state.
+    fn changed(&self, previous: &Self) -> PropMap {
+        let mut changed = PropMap::new();
+        let mut insert = |name: &str, value: Box<dyn RefArg>| {
+            changed.insert(name.to_string(), Variant(value));
+        };
+        if self.count != previous.count {
+            insert("Count", Box::new(self.count));
+        }
+        if self.unseen_count != previous.unseen_count {
+            insert("UnseenCount", Box::new(self.unseen_count));
+        }
+        if self.critical_count != previous.critical_count {
+            insert("CriticalCount", Box::new(self.critical_count));
+        }
+        if self.paused != previous.paused {
+            insert("Paused", Box::new(self.paused));
+        }
+        changed
+    }
+}
+
 /// D-Bus notification implementation.
 ///
 /// <https://specifications.freedesktop.org/notification-spec/latest/ar01s09.html>
@@ -64,45 +287,76 @@ impl dbus_server::OrgFreedesktopNotifications for DbusNotification {
         body: String,
         actions: Vec<String>,
         hints: dbus::arg::PropMap,
-        _expire_timeout: i32,
+        expire_timeout: i32,
     ) -> Result<u32, dbus::MethodErr> {
-        let id = if replaces_id == 0 {
-            ID_COUNT.fetch_add(1, Ordering::Relaxed)
-        } else {
-            replaces_id
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|e| dbus::MethodErr::failed(&e))?
+            .as_secs();
+        // Per the spec, `image-data` takes precedence over `image-path`.
+        let image_data = ["image-data", "image_data", "icon_data"]
+            .iter()
+            .find_map(|key| hints.get(*key))
+            .and_then(|v| ImageData::from_refarg(&*v.0));
+        let image = match hints.get("image-path").or_else(|| hints.get("image_path")) {
+            Some(path) if image_data.is_none() => path
+                .as_str()
+                .map(|path| path.strip_prefix("file://").unwrap_or(path))
+                .filter(|path| path.starts_with('/'))
+                .map(PathBuf::from),
+            _ => None,
         };
+        let hints: HashMap<String, Hint> = hints
+            .iter()
+            .map(|(k, v)| (k.clone(), Hint::from(&*v.0)))
+            .collect();
+        // The main loop tells whether `replaces_id` is known, once it stored the notifications sent before.
         let notification = Notification {
-            id,
+            id: replaces_id,
             summary,
             body,
-            application,            
+            body_plain: String::new(),
+            body_pango: String::new(),
+            links: Vec::new(),
+            app_name: application.clone(),
+            desktop_entry: hints
+                .get("desktop-entry")
+                .and_then(Hint::as_str)
+                .filter(|entry| !entry.is_empty())
+                .map(String::from),
+            application,
             icon,
+            icon_path: None,
+            image,
+            image_data,
             urgency: hints
                 .get("urgency")
-                .and_then(|v| v.as_u64())
+                .and_then(Hint::as_u64)
                 .map(|v| v.into())
                 .unwrap_or_default(),
             actions,
-            hints: hints
-                .into_iter()
-                .map(|(k, v)| (k, v.as_str().unwrap_or_default().to_string()))
-                .collect(),
-            timestamp: SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .map_err(|e| dbus::MethodErr::failed(&e))?
-                .as_secs(),
+            resident: hints
+                .get("resident")
+                .and_then(Hint::as_bool)
+                .unwrap_or_default(),
+            seen: false,
+            transient: hints
+                .get("transient")
+                .and_then(Hint::as_bool)
+                .unwrap_or_default(),
+            skip_history: false,
+            expire_timeout,
+            hints,
+            timestamp,
+            updated_at: timestamp,
         };
-        debug!("Received notification {} from dbus", notification.id);
-
-        match self.sender.send(Action::Show(notification)) {
-            Ok(_) => Ok(id),
-            Err(e) => Err(dbus::MethodErr::failed(&e)),
-        }
+        debug!("Received notification from dbus, replacing {}", replaces_id);
+        request(&self.sender, |reply| Action::Show(Box::new(notification), reply))
     }
 
     fn close_notification(&mut self, id: u32) -> Result<(), dbus::MethodErr> {
         debug!("Received close signal for notification {}", id);
-        match self.sender.send(Action::Close(Some(id))) {
+        match self.sender.send(Action::Close(id)) {
             Ok(_) => Ok(()),
             Err(e) => Err(dbus::MethodErr::failed(&e)),
         }
@@ -120,6 +374,105 @@ impl dbus_server::OrgFreedesktopNotifications for DbusNotification {
     }
 }
 
+/// D-Bus control implementation, see `org.armesto.Control1` in `dbus/introspection.xml`.
+pub struct DbusControl {
+    sender: Sender<Action>,
+    db: NotificationStore,
+}
+
+impl dbus_server::OrgArmestoControl1 for DbusControl {
+    fn history(&mut self) -> Result<u32, dbus::MethodErr> {
+        let id = request(&self.sender, Action::ShowLast)?;
+        Ok(id.unwrap_or_default())
+    }
+
+    fn close(&mut self) -> Result<u32, dbus::MethodErr> {
+        let id = request(&self.sender, Action::CloseLast)?;
+        Ok(id.unwrap_or_default())
+    }
+
+    fn close_all(&mut self) -> Result<String, dbus::MethodErr> {
+        self.sender
+            .send(Action::CloseAll)
+            .map_err(|e| MethodErr::failed(&e))?;
+        Ok(String::from("close all signal sent"))
+    }
+
+    fn invoke_action(&mut self, id: u32, action_key: String) -> Result<String, dbus::MethodErr> {
+        self.sender
+            .send(Action::Invoke(id, action_key))
+            .map_err(|e| MethodErr::failed(&e))?;
+        Ok(String::from("invoke action signal sent"))
+    }
+
+    fn set_paused(&mut self, paused: bool) -> Result<bool, dbus::MethodErr> {
+        request(&self.sender, |reply| Action::Pause(Some(paused), reply))
+    }
+
+    fn toggle_pause(&mut self) -> Result<bool, dbus::MethodErr> {
+        request(&self.sender, |reply| Action::Pause(None, reply))
+    }
+
+    fn list_notifications(&mut self) -> Result<Vec<PropMap>, dbus::MethodErr> {
+        Ok(self.db.items().iter().map(notification_properties).collect())
+    }
+
+    fn get_notification(&mut self, id: u32) -> Result<PropMap, dbus::MethodErr> {
+        self.db
+            .get(id)
+            .map(|notification| notification_properties(&notification))
+            .ok_or_else(|| {
+                MethodErr::from((
+                    "org.armesto.Control1.Error.UnknownNotification",
+                    format!("No notification with id {id}"),
+                ))
+            })
+    }
+
+    fn count(&self) -> Result<u32, dbus::MethodErr> {
+        Ok(self.db.count() as u32)
+    }
+
+    fn unseen_count(&self) -> Result<u32, dbus::MethodErr> {
+        Ok(self.db.unseen_count() as u32)
+    }
+
+    fn critical_count(&self) -> Result<u32, dbus::MethodErr> {
+        Ok(self.db.critical_count() as u32)
+    }
+
+    fn paused(&self) -> Result<bool, dbus::MethodErr> {
+        Ok(self.db.is_paused())
+    }
+
+    fn version(&self) -> Result<String, dbus::MethodErr> {
+        Ok(SERVER_INFO[2].to_string())
+    }
+}
+
+/// Converts a notification into the dictionary returned by the control interface.
+fn notification_properties(notification: &Notification) -> PropMap {
+    let hints: PropMap = notification
+        .hints
+        .iter()
+        .map(|(key, hint)| (key.clone(), Variant(hint.to_refarg())))
+        .collect();
+
+    let mut properties = PropMap::new();
+    let mut insert = |name: &str, value: Box<dyn RefArg>| {
+        properties.insert(name.to_string(), Variant(value));
+    };
+    insert("id", Box::new(notification.id));
+    insert("summary", Box::new(notification.summary.clone()));
+    insert("body", Box::new(notification.body.clone()));
+    insert("app", Box::new(notification.app_name.clone()));
+    insert("urgency", Box::new(notification.urgency as u8));
+    insert("timestamp", Box::new(notification.timestamp));
+    insert("actions", Box::new(notification.actions.clone()));
+    insert("hints", Box::new(hints));
+    properties
+}
+
 /// Wrapper for a [`D-Bus connection`] and [`server`] handler.
 ///
 /// [`D-Bus connection`]: Connection
@@ -136,7 +489,10 @@ impl DbusServer {
     pub fn init() -> error::Result<Self> {
         debug!("D-Bus server information: {:#?}", SERVER_INFO);
         debug!("D-Bus server capabilities: {:?}", SERVER_CAPABILITIES);
-        let connection = Connection::new_session()?;
+        let mut channel = Channel::get_private(BusType::Session)?;
+        // The thread waits for the connection along with the queued signals.
+        channel.set_watch_enabled(true);
+        let connection = Connection::from(channel);
         let crossroads = Crossroads::new();
         Ok(Self {
             connection,
@@ -146,10 +502,16 @@ impl DbusServer {
 
     /// Registers a handler for handling D-Bus notifications.
     ///
-    /// Handles the incoming messages in a blocking manner.
+    /// Handles the incoming messages in a blocking manner and emits
+    /// the pending [`Signal`]s as soon as they are queued, along with
+    /// `PropertiesChanged` if the state of the store changed.
+    ///
+    /// Changes of the store are checked at least once per `timeout`.
     pub fn register_notification_handler(
         mut self,
         sender: Sender<Action>,
+        signals: SignalReceiver,
+        db: NotificationStore,
         timeout: Duration,
     ) -> Result<(), Error> {
         let reply = self.connection
@@ -167,30 +529,15 @@ impl DbusServer {
                 sender: sender.clone(),
             },
         );
-        let token = self.crossroads.register(NOTIFICATION_INTERFACE, |builder| {
-            let sender_cloned = sender.clone();
-            builder.method("History", (), ("reply",), move |_, _, ()| {
-                sender_cloned
-                    .send(Action::ShowLast)
-                    .map_err(|e| MethodErr::failed(&e))?;
-                Ok((String::from("history signal sent"),))
-            });
-            let sender_cloned = sender.clone();
-            builder.method("Close", (), ("reply",), move |_, _, (): ()| {
-                sender_cloned
-                    .send(Action::Close(None))
-                    .map_err(|e| MethodErr::failed(&e))?;
-                Ok((String::from("close signal sent"),))
-            });
-            builder.method("CloseAll", (), ("reply",), move |_, _, ()| {
-                sender
-                    .send(Action::CloseAll)
-                    .map_err(|e| MethodErr::failed(&e))?;
-                Ok((String::from("close all signal sent"),))
-            });
-        });
-        self.crossroads
-            .insert(format!("{NOTIFICATION_PATH}/ctl"), &[token], ());
+        let token = dbus_server::register_org_armesto_control1(&mut self.crossroads);
+        self.crossroads.insert(
+            CONTROL_PATH,
+            &[token],
+            DbusControl {
+                sender,
+                db: db.clone(),
+            },
+        );
         self.connection.start_receive(
             MatchRule::new_method_call(),
             Box::new(move |message, connection| {
@@ -200,12 +547,52 @@ impl DbusServer {
                 true
             }),
         );
+        let mut revision = db.revision();
+        let mut properties = ControlProperties::read(&db);
         loop {
-            self.connection.process(timeout)?;
+            // Dispatches every message received so far, not only the first one.
+            while self.connection.process(Duration::ZERO)? {}
+            for signal in signals.receiver.try_iter() {
+                debug!("Emitting signal {:?}", signal);
+                if self.connection.channel().send(signal.into_message()).is_err() {
+                    warn!("Unable to emit D-Bus signal");
+                }
+            }
+
+            if db.revision() != revision {
+                revision = db.revision();
+                let current = ControlProperties::read(&db);
+                let changed = current.changed(&properties);
+                properties = current;
+                if !changed.is_empty() {
+                    debug!("Emitting changed properties {:?}", changed.keys());
+                    let message = PropertiesPropertiesChanged {
+                        interface_name: CONTROL_INTERFACE.to_string(),
+                        changed_properties: changed,
+                        invalidated_properties: Vec::new(),
+                    }
+                    .to_emit_message(&dbus::Path::from(CONTROL_PATH));
+                    if self.connection.channel().send(message).is_err() {
+                        warn!("Unable to emit D-Bus signal");
+                    }
+                }
+            }
+
+            self.connection.channel().flush();
+            let watch = self.connection.channel().watch();
+            signals.wait(watch.fd, watch.write, timeout)?;
         }
     }
 }
 
+/// Sends an action to the main loop and waits for its reply.
+fn request<T>(
+    sender: &Sender<Action>,
+    action: impl FnOnce(Sender<T>) -> Action,
+) -> Result<T, MethodErr> {
+    Action::request(sender, action).ok_or_else(|| MethodErr::failed("main loop is not running"))
+}
+
 /// Wrapper for a [`D-Bus connection`] without the server part.
 ///
 /// [`D-Bus connection`]: Connection
@@ -240,7 +627,7 @@ impl DbusClient {
             Duration::from_millis(1000),
             &self.connection,
         );
-        proxy.method_call(
+        proxy.method_call::<(), _, _, _>(
             NOTIFICATION_INTERFACE,
             "Notify",
             (
@@ -271,7 +658,40 @@ impl DbusClient {
             timeout,
             &self.connection,
         );
-        proxy.method_call(NOTIFICATION_INTERFACE, "CloseNotification", (id,))?;
+        proxy.method_call::<(), _, _, _>(NOTIFICATION_INTERFACE, "CloseNotification", (id,))?;
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    #[test]
+    fn signal_channel_wakes_up() {
+        let (sender, receiver) = signal_channel().expect("Creates channel");
+        let (connection, _peer) = UnixStream::pair().expect("Creates connection");
+
+        let start = Instant::now();
+        receiver
+            .wait(connection.as_raw_fd(), false, Duration::from_millis(50))
+            .expect("Waits");
+        assert!(start.elapsed() >= Duration::from_millis(50), "idle until the timeout");
+
+        sender.send(Signal::NotificationAdded(1)).expect("Queues signal");
+        sender.wake();
+        let start = Instant::now();
+        receiver
+            .wait(connection.as_raw_fd(), false, Duration::from_secs(5))
+            .expect("Waits");
+        assert!(start.elapsed() < Duration::from_secs(1), "woken up by the signal");
+        assert_eq!(receiver.receiver.try_iter().count(), 1);
+
+        let start = Instant::now();
+        receiver
+            .wait(connection.as_raw_fd(), false, Duration::from_millis(50))
+            .expect("Waits");
+        assert!(start.elapsed() >= Duration::from_millis(50), "wake-ups are consumed");
+    }
+}
@@ -0,0 +1,149 @@
+use crate::keyfile::KeyFile;
+use crate::xdg;
+use log::debug;
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Group holding the application metadata in a `.desktop` file.
+const DESKTOP_ENTRY_GROUP: &str = "Desktop Entry";
+
+/// Application metadata read from a `.desktop` file.
+///
+/// See [Desktop Entry Specification](https://specifications.freedesktop.org/desktop-entry-spec/latest/)
+#[derive(Clone, Debug, PartialEq)]
+pub struct DesktopEntry {
+    /// human-friendly name of the application
+    pub name: String,
+    /// icon name or path of the application
+    pub icon: Option<String>,
+}
+
+impl DesktopEntry {
+    /// Reads the entry from the content of a `.desktop` file.
+    ///
+    /// Returns `None` for hidden entries and entries without a name.
+    pub fn parse(content: &str) -> Option<Self> {
+        let keyfile = KeyFile::parse(content);
+        if keyfile.get(DESKTOP_ENTRY_GROUP, "Hidden") == Some("true") {
+            return None;
+        }
+        let name = keyfile
+            .get(DESKTOP_ENTRY_GROUP, "Name")
+            .filter(|name| !name.is_empty())?;
+
+        Some(Self {
+            name: name.to_string(),
+            icon: keyfile
+                .get(DESKTOP_ENTRY_GROUP, "Icon")
+                .filter(|icon| !icon.is_empty())
+                .map(String::from),
+        })
+    }
+}
+
+/// Looks up desktop entries by their desktop file id, caching the results.
+#[derive(Debug)]
+pub struct DesktopEntries {
+    dirs: Vec<PathBuf>,
+    cache: HashMap<String, Option<DesktopEntry>>,
+}
+
+impl DesktopEntries {
+    /// Searches the `applications` directories of `$XDG_DATA_DIRS`.
+    pub fn new() -> Self {
+        Self::with_dirs(
+            xdg::data_dirs()
+                .into_iter()
+                .map(|dir| dir.join("applications"))
+                .collect(),
+        )
+    }
+
+    /// Searches the given directories, in order of preference.
+    pub fn with_dirs(dirs: Vec<PathBuf>) -> Self {
+        Self {
+            dirs,
+            cache: HashMap::new(),
+        }
+    }
+
+    /// Returns the entry for the value of a `desktop-entry` hint.
+    ///
+    /// The `.desktop` suffix is optional. Ids containing dashes are also
+    /// looked up in subdirectories, e.g. `kde-foo` in `kde/foo.desktop`.
+    pub fn lookup(&mut self, id: &str) -> Option<DesktopEntry> {
+        let id = id.trim().trim_end_matches(".desktop");
+        if id.is_empty() || id.contains('/') {
+            return None;
+        }
+        if let Some(entry) = self.cache.get(id) {
+            return entry.clone();
+        }
+
+        let entry = self.find(id).or_else(|| self.find(&id.to_lowercase()));
+        debug!("resolved desktop entry '{}' to {:?}", id, entry);
+        self.cache.insert(id.to_string(), entry.clone());
+        entry
+    }
+
+    fn find(&self, id: &str) -> Option<DesktopEntry> {
+        let mut candidates = vec![PathBuf::from(format!("{id}.desktop"))];
+        candidates.extend(
+            id.match_indices('-')
+                .map(|(index, _)| PathBuf::from(&id[..index]).join(format!("{}.desktop", &id[index + 1..]))),
+        );
+
+        self.dirs
+            .iter()
+            .flat_map(|dir| candidates.iter().map(move |candidate| dir.join(candidate)))
+            .filter_map(|path| fs::read_to_string(path).ok())
+            .find_map(|content| DesktopEntry::parse(&content))
+    }
+}
+
+impl Default for DesktopEntries {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn desktop_entry_parse() {
+        assert_eq!(
+            DesktopEntry::parse("[Desktop Entry]\nName=Mail\nIcon=mail-client\n"),
+            Some(DesktopEntry {
+                name: "Mail".to_string(),
+                icon: Some("mail-client".to_string())
+            })
+        );
+        assert_eq!(DesktopEntry::parse("[Desktop Entry]\nName=Mail\nHidden=true\n"), None);
+        assert_eq!(DesktopEntry::parse("[Desktop Action new]\nName=New\n"), None);
+    }
+
+    #[test]
+    fn desktop_entries_lookup() {
+        let dir = env::temp_dir().join(format!("armesto-desktop-test-{}", std::process::id()));
+        fs::create_dir_all(dir.join("kde")).expect("Creates directory");
+        fs::write(dir.join("org.example.Mail.desktop"), "[Desktop Entry]\nName=Mail\n").expect("Writes file");
+        fs::write(dir.join("chat.desktop"), "[Desktop Entry]\nName=Chat\n").expect("Writes file");
+        fs::write(dir.join("kde/konsole.desktop"), "[Desktop Entry]\nName=Konsole\n").expect("Writes file");
+
+        let mut unit = DesktopEntries::with_dirs(vec![dir.clone()]);
+
+        let name = |entry: Option<DesktopEntry>| entry.map(|e| e.name);
+        assert_eq!(name(unit.lookup("org.example.Mail")), Some("Mail".to_string()));
+        assert_eq!(name(unit.lookup("org.example.Mail.desktop")), Some("Mail".to_string()));
+        assert_eq!(name(unit.lookup("Chat")), Some("Chat".to_string()), "case fallback");
+        assert_eq!(name(unit.lookup("kde-konsole")), Some("Konsole".to_string()), "subdirectory");
+        assert_eq!(unit.lookup("missing"), None);
+        assert_eq!(unit.lookup("../chat"), None);
+
+        fs::remove_dir_all(dir).expect("Removes test directory");
+    }
+}
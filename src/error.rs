@@ -30,6 +30,8 @@ pub enum Error {
     Config(String),
     #[error("Init error")]
     InitializationError,
+    #[error("Received signal {0}")]
+    Signal(i32),
 }
 
 /// Type alias for the standard [`Result`] type.
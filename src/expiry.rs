@@ -0,0 +1,195 @@
+use crate::notification::{Notification, Urgency};
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+/// Default expiration timeouts, used when the sender leaves the choice to the server.
+#[derive(Clone, Copy, Debug)]
+pub struct ExpiryTimeouts {
+    /// timeout in milliseconds for low urgency notifications, 0 to never expire
+    pub low: u32,
+    /// timeout in milliseconds for normal urgency notifications, 0 to never expire
+    pub normal: u32,
+    /// timeout in milliseconds for critical notifications, 0 to never expire
+    pub critical: u32,
+    /// critical notifications never expire, regardless of the requested timeout
+    pub sticky_critical: bool,
+}
+
+impl Default for ExpiryTimeouts {
+    fn default() -> Self {
+        Self {
+            low: 10000,
+            normal: 10000,
+            critical: 0,
+            sticky_critical: true,
+        }
+    }
+}
+
+impl ExpiryTimeouts {
+    /// Resolves the time after which a notification expires.
+    ///
+    /// Per the spec, an `expire_timeout` of -1 lets the server decide and 0 never expires.
+    /// Returns `None` if the notification should not expire.
+    pub fn resolve(&self, urgency: Urgency, expire_timeout: i32) -> Option<Duration> {
+        if urgency == Urgency::Critical && self.sticky_critical {
+            return None;
+        }
+
+        let millis = match expire_timeout {
+            0 => return None,
+            t if t < 0 => match urgency {
+                Urgency::Low => self.low,
+                Urgency::Normal => self.normal,
+                Urgency::Critical => self.critical,
+            },
+            t => t.unsigned_abs(),
+        };
+
+        if millis == 0 {
+            None
+        } else {
+            Some(Duration::from_millis(millis.into()))
+        }
+    }
+}
+
+/// Keeps track of when notifications expire.
+#[derive(Debug, Default)]
+pub struct ExpiryScheduler {
+    timeouts: ExpiryTimeouts,
+    deadlines: HashMap<u32, Instant>,
+}
+
+impl ExpiryScheduler {
+    /// Creates a scheduler using the given default timeouts
+    pub fn new(timeouts: ExpiryTimeouts) -> Self {
+        Self {
+            timeouts,
+            deadlines: HashMap::new(),
+        }
+    }
+
+    /// Schedules the expiry of a notification, replacing any previous deadline for its id.
+    pub fn schedule(&mut self, notification: &Notification, now: Instant) {
+        match self
+            .timeouts
+            .resolve(notification.urgency, notification.expire_timeout)
+        {
+            Some(timeout) => {
+                self.deadlines.insert(notification.id, now + timeout);
+            }
+            None => self.cancel(notification.id),
+        }
+    }
+
+    /// Forgets the deadline of a notification
+    pub fn cancel(&mut self, id: u32) {
+        self.deadlines.remove(&id);
+    }
+
+    /// Returns the time left until the next notification expires, if any.
+    pub fn next_timeout(&self, now: Instant) -> Option<Duration> {
+        self.deadlines
+            .values()
+            .min()
+            .map(|deadline| deadline.saturating_duration_since(now))
+    }
+
+    /// Removes and returns the ids of all notifications that have expired.
+    pub fn expired(&mut self, now: Instant) -> Vec<u32> {
+        let mut expired: Vec<u32> = self
+            .deadlines
+            .iter()
+            .filter(|(_, deadline)| **deadline <= now)
+            .map(|(id, _)| *id)
+            .collect();
+        expired.sort_unstable();
+
+        for id in &expired {
+            self.deadlines.remove(id);
+        }
+        expired
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resolve_timeouts() {
+        let unit = ExpiryTimeouts::default();
+
+        assert_eq!(unit.resolve(Urgency::Normal, 0), None, "0 never expires");
+        assert_eq!(
+            unit.resolve(Urgency::Normal, 2500),
+            Some(Duration::from_millis(2500))
+        );
+        assert_eq!(
+            unit.resolve(Urgency::Low, -1),
+            Some(Duration::from_millis(10000)),
+            "-1 uses the default for the urgency"
+        );
+        assert_eq!(
+            unit.resolve(Urgency::Critical, 2500),
+            None,
+            "critical notifications are sticky"
+        );
+    }
+
+    #[test]
+    fn resolve_non_sticky_critical() {
+        let unit = ExpiryTimeouts {
+            critical: 30000,
+            sticky_critical: false,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            unit.resolve(Urgency::Critical, -1),
+            Some(Duration::from_millis(30000))
+        );
+        assert_eq!(
+            unit.resolve(Urgency::Critical, 2500),
+            Some(Duration::from_millis(2500))
+        );
+    }
+
+    #[test]
+    fn scheduler_expires_in_order() {
+        let mut unit = ExpiryScheduler::default();
+        let now = Instant::now();
+
+        assert_eq!(unit.next_timeout(now), None, "nothing scheduled");
+
+        unit.schedule(&notification(1, 1000), now);
+        unit.schedule(&notification(2, 500), now);
+        unit.schedule(&notification(3, 0), now);
+
+        assert_eq!(unit.next_timeout(now), Some(Duration::from_millis(500)));
+        assert!(unit.expired(now).is_empty());
+        assert_eq!(unit.expired(now + Duration::from_millis(500)), vec![2]);
+        assert_eq!(unit.expired(now + Duration::from_secs(5)), vec![1]);
+        assert_eq!(unit.next_timeout(now), None, "all expired");
+    }
+
+    #[test]
+    fn scheduler_cancel() {
+        let mut unit = ExpiryScheduler::default();
+        let now = Instant::now();
+
+        unit.schedule(&notification(1, 1000), now);
+        unit.cancel(1);
+
+        assert!(unit.expired(now + Duration::from_secs(5)).is_empty());
+    }
+
+    fn notification(id: u32, expire_timeout: i32) -> Notification {
+        Notification {
+            id,
+            expire_timeout,
+            ..Default::default()
+        }
+    }
+}
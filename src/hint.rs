@@ -0,0 +1,236 @@
+use dbus::arg::{ArgType, PropMap, RefArg, Variant};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+
+/// Value of a notification hint, keeping the D-Bus type of the value.
+///
+/// Hints are serialized as an object holding the type and the value, e.g.
+/// `{"type":"int","value":42}`. The type is one of `string`, `int`, `uint`,
+/// `bool`, `byte`, `double` or `binary`.
+///
+/// Binary values such as `image-data` are not serialized. They are replaced by a
+/// reference holding their D-Bus signature and the number of bytes they contain,
+/// e.g. `{"type":"binary","value":{"signature":"(iiibiiay)","length":4096}}`.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", content = "value", rename_all = "lowercase")]
+pub enum Hint {
+    /// string, object path or signature
+    String(String),
+    /// signed integer
+    Int(i64),
+    /// unsigned integer
+    UInt(u64),
+    /// boolean
+    Bool(bool),
+    /// single byte
+    Byte(u8),
+    /// floating point number
+    Double(f64),
+    /// arrays, structs and other compound values
+    Binary {
+        /// D-Bus signature of the value
+        signature: String,
+        /// number of bytes in the value's byte arrays
+        length: usize,
+    },
+}
+
+impl Hint {
+    /// Returns the value if the hint is a string.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Hint::String(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns the value if the hint is a non-negative integer.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Hint::Byte(value) => Some(u64::from(*value)),
+            Hint::UInt(value) => Some(*value),
+            Hint::Int(value) => u64::try_from(*value).ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns the value if the hint is a boolean.
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Hint::Bool(value) => Some(*value),
+            _ => None,
+        }
+    }
+
+    /// Converts the hint back into a D-Bus value.
+    ///
+    /// Binary values are returned as their reference, a `a{sv}` dictionary
+    /// holding the `signature` and the `length`.
+    pub fn to_refarg(&self) -> Box<dyn RefArg> {
+        match self {
+            Hint::String(value) => Box::new(value.clone()),
+            Hint::Int(value) => Box::new(*value),
+            Hint::UInt(value) => Box::new(*value),
+            Hint::Bool(value) => Box::new(*value),
+            Hint::Byte(value) => Box::new(*value),
+            Hint::Double(value) => Box::new(*value),
+            Hint::Binary { signature, length } => {
+                let mut reference = PropMap::new();
+                reference.insert("signature".to_string(), Variant(Box::new(signature.clone())));
+                reference.insert("length".to_string(), Variant(Box::new(*length as u64)));
+                Box::new(reference)
+            }
+        }
+    }
+}
+
+impl Display for Hint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Hint::String(value) => write!(f, "{value}"),
+            Hint::Int(value) => write!(f, "{value}"),
+            Hint::UInt(value) => write!(f, "{value}"),
+            Hint::Bool(value) => write!(f, "{value}"),
+            Hint::Byte(value) => write!(f, "{value}"),
+            Hint::Double(value) => write!(f, "{value}"),
+            Hint::Binary { signature, length } => write!(f, "binary({signature}, {length} bytes)"),
+        }
+    }
+}
+
+impl From<&dyn RefArg> for Hint {
+    fn from(arg: &dyn RefArg) -> Self {
+        match arg.arg_type() {
+            ArgType::Variant => match arg.as_iter().and_then(|mut i| i.next()) {
+                Some(inner) => Hint::from(inner),
+                None => binary(arg),
+            },
+            ArgType::String | ArgType::ObjectPath | ArgType::Signature => {
+                Hint::String(arg.as_str().unwrap_or_default().to_string())
+            }
+            ArgType::Boolean => Hint::Bool(arg.as_u64().unwrap_or_default() != 0),
+            ArgType::Byte => Hint::Byte(
+                arg.as_u64()
+                    .and_then(|v| u8::try_from(v).ok())
+                    .unwrap_or_default(),
+            ),
+            ArgType::Int16 | ArgType::Int32 | ArgType::Int64 => {
+                Hint::Int(arg.as_i64().unwrap_or_default())
+            }
+            ArgType::UInt16 | ArgType::UInt32 | ArgType::UInt64 => {
+                Hint::UInt(arg.as_u64().unwrap_or_default())
+            }
+            ArgType::Double => Hint::Double(arg.as_f64().unwrap_or_default()),
+            _ => binary(arg),
+        }
+    }
+}
+
+/// Creates a binary reference for a compound value.
+fn binary(arg: &dyn RefArg) -> Hint {
+    Hint::Binary {
+        signature: arg.signature().to_string(),
+        length: byte_length(arg),
+    }
+}
+
+/// Counts the bytes contained in a value.
+fn byte_length(arg: &dyn RefArg) -> usize {
+    match arg.arg_type() {
+        ArgType::Byte => 1,
+        _ => arg
+            .as_iter()
+            .map(|items| items.map(byte_length).sum())
+            .unwrap_or_default(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn hint_from_basic_types() {
+        assert_eq!(hint(String::from("im.received")), Hint::String("im.received".to_string()));
+        assert_eq!(hint(-5_i32), Hint::Int(-5));
+        assert_eq!(hint(75_u32), Hint::UInt(75));
+        assert_eq!(hint(true), Hint::Bool(true));
+        assert_eq!(hint(2_u8), Hint::Byte(2));
+        assert_eq!(hint(0.5_f64), Hint::Double(0.5));
+        assert_eq!(hint(Variant(7_i32)), Hint::Int(7), "nested variants are unwrapped");
+    }
+
+    #[test]
+    fn hint_from_compound_types() {
+        assert_eq!(
+            hint(vec![1_u8, 2, 3]),
+            Hint::Binary {
+                signature: "ay".to_string(),
+                length: 3
+            }
+        );
+
+        let image: VecDeque<Box<dyn RefArg>> = VecDeque::from(vec![
+            Box::new(1_i32) as Box<dyn RefArg>,
+            Box::new(1_i32),
+            Box::new(4_i32),
+            Box::new(true),
+            Box::new(8_i32),
+            Box::new(4_i32),
+            Box::new(vec![0_u8, 0, 0, 255]),
+        ]);
+        assert_eq!(
+            hint(image),
+            Hint::Binary {
+                signature: "(iiibiiay)".to_string(),
+                length: 4
+            }
+        );
+    }
+
+    #[test]
+    fn hint_serialization() {
+        let json = |hint: Hint| serde_json::to_string(&hint).expect("Serializing hint");
+
+        assert_eq!(json(Hint::String("x".to_string())), r#"{"type":"string","value":"x"}"#);
+        assert_eq!(json(Hint::Int(-1)), r#"{"type":"int","value":-1}"#);
+        assert_eq!(json(Hint::UInt(1)), r#"{"type":"uint","value":1}"#);
+        assert_eq!(json(Hint::Bool(true)), r#"{"type":"bool","value":true}"#);
+        assert_eq!(json(Hint::Byte(2)), r#"{"type":"byte","value":2}"#);
+        assert_eq!(json(Hint::Double(0.5)), r#"{"type":"double","value":0.5}"#);
+        assert_eq!(
+            json(Hint::Binary {
+                signature: "ay".to_string(),
+                length: 3
+            }),
+            r#"{"type":"binary","value":{"signature":"ay","length":3}}"#
+        );
+    }
+
+    #[test]
+    fn hint_to_refarg() {
+        let roundtrip = |value: Hint| Hint::from(&*value.to_refarg());
+
+        assert_eq!(roundtrip(Hint::String("x".to_string())), Hint::String("x".to_string()));
+        assert_eq!(roundtrip(Hint::Int(-1)), Hint::Int(-1));
+        assert_eq!(roundtrip(Hint::UInt(1)), Hint::UInt(1));
+        assert_eq!(roundtrip(Hint::Bool(true)), Hint::Bool(true));
+        assert_eq!(roundtrip(Hint::Byte(2)), Hint::Byte(2));
+        assert_eq!(roundtrip(Hint::Double(0.5)), Hint::Double(0.5));
+        assert_eq!(
+            Hint::Binary {
+                signature: "ay".to_string(),
+                length: 3
+            }
+            .to_refarg()
+            .signature()
+            .to_string(),
+            "a{sv}"
+        );
+    }
+
+    fn hint<T: RefArg>(value: T) -> Hint {
+        Hint::from(&value as &dyn RefArg)
+    }
+}
@@ -0,0 +1,339 @@
+use crate::keyfile::KeyFile;
+use crate::xdg;
+use log::debug;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Theme that every icon theme falls back to.
+const FALLBACK_THEME: &str = "hicolor";
+
+/// Supported icon file extensions, in order of preference.
+const ICON_EXTENSIONS: [&str; 3] = ["png", "svg", "xpm"];
+
+/// How the icons of a theme directory may be scaled.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DirectoryType {
+    Fixed,
+    Scalable,
+    Threshold,
+}
+
+/// A subdirectory of an icon theme, holding icons of one size.
+#[derive(Clone, Debug)]
+struct ThemeDirectory {
+    path: String,
+    size: u32,
+    scale: u32,
+    kind: DirectoryType,
+    min_size: u32,
+    max_size: u32,
+    threshold: u32,
+}
+
+impl ThemeDirectory {
+    /// Reads the description of a directory from `index.theme`.
+    fn parse(index: &KeyFile, path: &str) -> Option<Self> {
+        let size = index.get_u32(path, "Size")?;
+        let kind = match index.get(path, "Type") {
+            Some("Fixed") => DirectoryType::Fixed,
+            Some("Scalable") => DirectoryType::Scalable,
+            _ => DirectoryType::Threshold,
+        };
+        Some(Self {
+            path: path.to_string(),
+            size,
+            scale: index.get_u32(path, "Scale").unwrap_or(1),
+            kind,
+            min_size: index.get_u32(path, "MinSize").unwrap_or(size),
+            max_size: index.get_u32(path, "MaxSize").unwrap_or(size),
+            threshold: index.get_u32(path, "Threshold").unwrap_or(2),
+        })
+    }
+
+    /// `DirectoryMatchesSize` of the spec.
+    fn matches_size(&self, size: u32, scale: u32) -> bool {
+        if self.scale != scale {
+            return false;
+        }
+        match self.kind {
+            DirectoryType::Fixed => self.size == size,
+            DirectoryType::Scalable => self.min_size <= size && size <= self.max_size,
+            DirectoryType::Threshold => {
+                self.size.saturating_sub(self.threshold) <= size
+                    && size <= self.size + self.threshold
+            }
+        }
+    }
+
+    /// `DirectorySizeDistance` of the spec.
+    fn size_distance(&self, size: u32, scale: u32) -> u32 {
+        let (min, max) = match self.kind {
+            DirectoryType::Fixed => (self.size, self.size),
+            DirectoryType::Scalable => (self.min_size, self.max_size),
+            DirectoryType::Threshold => (
+                self.size.saturating_sub(self.threshold),
+                self.size + self.threshold,
+            ),
+        };
+        let wanted = size * scale;
+        if wanted < min * self.scale {
+            min * self.scale - wanted
+        } else {
+            wanted.saturating_sub(max * self.scale)
+        }
+    }
+}
+
+/// Icon theme, as described by its `index.theme`.
+#[derive(Clone, Debug, Default)]
+struct Theme {
+    directories: Vec<ThemeDirectory>,
+    parents: Vec<String>,
+}
+
+/// Resolves icon names to files, following the
+/// [Icon Theme Specification](https://specifications.freedesktop.org/icon-theme-spec/latest/).
+///
+/// Themes and resolved icons are cached per name.
+#[derive(Debug)]
+pub struct IconResolver {
+    theme: String,
+    size: u32,
+    base_dirs: Vec<PathBuf>,
+    fallback_dirs: Vec<PathBuf>,
+    themes: HashMap<String, Option<Theme>>,
+    icons: HashMap<String, Option<PathBuf>>,
+}
+
+impl IconResolver {
+    /// Creates a resolver for the given theme and icon size, searching the XDG icon directories.
+    pub fn new(theme: String, size: u32) -> Self {
+        let mut base_dirs: Vec<PathBuf> = xdg::home()
+            .map(|home| home.join(".icons"))
+            .into_iter()
+            .collect();
+        base_dirs.extend(xdg::data_dirs().into_iter().map(|dir| dir.join("icons")));
+
+        Self::with_dirs(theme, size, base_dirs, vec![PathBuf::from("/usr/share/pixmaps")])
+    }
+
+    /// Creates a resolver searching themes in `base_dirs` and unthemed icons in `fallback_dirs`.
+    pub fn with_dirs(
+        theme: String,
+        size: u32,
+        base_dirs: Vec<PathBuf>,
+        fallback_dirs: Vec<PathBuf>,
+    ) -> Self {
+        Self {
+            theme,
+            size,
+            base_dirs,
+            fallback_dirs,
+            themes: HashMap::new(),
+            icons: HashMap::new(),
+        }
+    }
+
+    /// Returns the path of the icon with the given name.
+    ///
+    /// Absolute paths and `file://` URIs are returned as is if the file exists.
+    pub fn resolve(&mut self, icon: &str) -> Option<PathBuf> {
+        if icon.is_empty() {
+            return None;
+        }
+        if let Some(path) = self.icons.get(icon) {
+            return path.clone();
+        }
+
+        let path = self.lookup(icon);
+        debug!("resolved icon '{}' to {:?}", icon, path);
+        self.icons.insert(icon.to_string(), path.clone());
+        path
+    }
+
+    /// `FindIcon` of the spec.
+    fn lookup(&mut self, icon: &str) -> Option<PathBuf> {
+        let path = icon.strip_prefix("file://").unwrap_or(icon);
+        if path.starts_with('/') {
+            return Some(PathBuf::from(path)).filter(|path| path.is_file());
+        }
+        if icon.contains('/') {
+            return None;
+        }
+
+        let mut visited = HashSet::new();
+        let theme = self.theme.clone();
+        self.lookup_in_theme(icon, &theme, &mut visited)
+            .or_else(|| self.lookup_in_theme(icon, FALLBACK_THEME, &mut visited))
+            .or_else(|| self.lookup_fallback(icon))
+    }
+
+    /// `FindIconHelper` of the spec, searching a theme and its parents.
+    fn lookup_in_theme(
+        &mut self,
+        icon: &str,
+        theme_name: &str,
+        visited: &mut HashSet<String>,
+    ) -> Option<PathBuf> {
+        if !visited.insert(theme_name.to_string()) {
+            return None;
+        }
+        let theme = self.theme(theme_name)?;
+
+        if let Some(path) = self.lookup_icon(icon, theme_name, &theme) {
+            return Some(path);
+        }
+        theme
+            .parents
+            .iter()
+            .find_map(|parent| self.lookup_in_theme(icon, parent, visited))
+    }
+
+    /// `LookupIcon` of the spec, preferring an exact size over the closest one.
+    fn lookup_icon(&self, icon: &str, theme_name: &str, theme: &Theme) -> Option<PathBuf> {
+        let exact = theme
+            .directories
+            .iter()
+            .filter(|directory| directory.matches_size(self.size, 1))
+            .find_map(|directory| self.find_in_directory(icon, theme_name, directory));
+        if exact.is_some() {
+            return exact;
+        }
+
+        let mut closest: Option<(u32, PathBuf)> = None;
+        for directory in &theme.directories {
+            let distance = directory.size_distance(self.size, 1);
+            if closest.as_ref().map_or(true, |(min, _)| distance < *min) {
+                if let Some(path) = self.find_in_directory(icon, theme_name, directory) {
+                    closest = Some((distance, path));
+                }
+            }
+        }
+        closest.map(|(_, path)| path)
+    }
+
+    /// Returns the first icon file found in a theme directory of any base directory.
+    fn find_in_directory(
+        &self,
+        icon: &str,
+        theme_name: &str,
+        directory: &ThemeDirectory,
+    ) -> Option<PathBuf> {
+        self.base_dirs
+            .iter()
+            .flat_map(|base| {
+                ICON_EXTENSIONS.iter().map(move |ext| {
+                    base.join(theme_name)
+                        .join(&directory.path)
+                        .join(format!("{icon}.{ext}"))
+                })
+            })
+            .find(|path| path.is_file())
+    }
+
+    /// `LookupFallbackIcon` of the spec, searching unthemed icons.
+    fn lookup_fallback(&self, icon: &str) -> Option<PathBuf> {
+        self.fallback_dirs
+            .iter()
+            .flat_map(|dir| ICON_EXTENSIONS.iter().map(move |ext| dir.join(format!("{icon}.{ext}"))))
+            .find(|path| path.is_file())
+    }
+
+    /// Returns the theme with the given name, reading its `index.theme` on first use.
+    fn theme(&mut self, name: &str) -> Option<Theme> {
+        if let Some(theme) = self.themes.get(name) {
+            return theme.clone();
+        }
+        let theme = self
+            .base_dirs
+            .iter()
+            .find_map(|base| read_index(&base.join(name).join("index.theme")))
+            .map(|index| parse_theme(&index, name));
+        self.themes.insert(name.to_string(), theme.clone());
+        theme
+    }
+}
+
+fn read_index(path: &Path) -> Option<KeyFile> {
+    fs::read_to_string(path).ok().map(|content| KeyFile::parse(&content))
+}
+
+fn parse_theme(index: &KeyFile, name: &str) -> Theme {
+    let mut directories = index.get_list("Icon Theme", "Directories", ',');
+    directories.extend(index.get_list("Icon Theme", "ScaledDirectories", ','));
+
+    let mut parents: Vec<String> = index
+        .get_list("Icon Theme", "Inherits", ',')
+        .into_iter()
+        .map(String::from)
+        .collect();
+    if parents.is_empty() && name != FALLBACK_THEME {
+        parents.push(FALLBACK_THEME.to_string());
+    }
+
+    Theme {
+        directories: directories
+            .into_iter()
+            .filter_map(|path| ThemeDirectory::parse(index, path))
+            .collect(),
+        parents,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn resolve_icons() {
+        let dir = env::temp_dir().join(format!("armesto-icon-test-{}", std::process::id()));
+        let icons = dir.join("icons");
+        let pixmaps = dir.join("pixmaps");
+
+        write(
+            &icons.join("child/index.theme"),
+            "[Icon Theme]\nInherits=parent\nDirectories=16x16/apps,48x48/apps\n\n[16x16/apps]\nSize=16\nType=Fixed\n\n[48x48/apps]\nSize=48\nType=Fixed\n",
+        );
+        write(&icons.join("child/16x16/apps/small.png"), "");
+        write(&icons.join("child/48x48/apps/small.png"), "");
+        write(&icons.join("child/48x48/apps/large.png"), "");
+        write(
+            &icons.join("parent/index.theme"),
+            "[Icon Theme]\nDirectories=scalable/apps\n\n[scalable/apps]\nSize=48\nMinSize=8\nMaxSize=512\nType=Scalable\n",
+        );
+        write(&icons.join("parent/scalable/apps/inherited.svg"), "");
+        write(
+            &icons.join("hicolor/index.theme"),
+            "[Icon Theme]\nDirectories=32x32/apps\n\n[32x32/apps]\nSize=32\n",
+        );
+        write(&icons.join("hicolor/32x32/apps/fallback.png"), "");
+        write(&pixmaps.join("unthemed.xpm"), "");
+
+        let mut unit = IconResolver::with_dirs("child".to_string(), 16, vec![icons.clone()], vec![pixmaps.clone()]);
+
+        assert_eq!(unit.resolve("small"), Some(icons.join("child/16x16/apps/small.png")), "exact size");
+        assert_eq!(unit.resolve("large"), Some(icons.join("child/48x48/apps/large.png")), "closest size");
+        assert_eq!(unit.resolve("inherited"), Some(icons.join("parent/scalable/apps/inherited.svg")), "inherited");
+        assert_eq!(unit.resolve("fallback"), Some(icons.join("hicolor/32x32/apps/fallback.png")), "hicolor");
+        assert_eq!(unit.resolve("unthemed"), Some(pixmaps.join("unthemed.xpm")), "unthemed");
+        assert_eq!(unit.resolve("missing"), None);
+        assert_eq!(unit.resolve(""), None);
+
+        let uri = format!("file://{}", pixmaps.join("unthemed.xpm").display());
+        assert_eq!(unit.resolve(&uri), Some(pixmaps.join("unthemed.xpm")), "file URI");
+
+        fs::remove_dir_all(dir).expect("Removes test directory");
+        assert_eq!(
+            unit.resolve("small"),
+            Some(icons.join("child/16x16/apps/small.png")),
+            "results are cached"
+        );
+    }
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().expect("Has parent")).expect("Creates directory");
+        fs::write(path, content).expect("Writes file");
+    }
+}
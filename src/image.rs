@@ -0,0 +1,375 @@
+use crate::notification::NotificationStore;
+use crate::xdg;
+use dbus::arg::RefArg;
+use log::{debug, warn};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Largest width or height of an image that is accepted.
+const MAX_IMAGE_DIMENSION: i32 = 4096;
+
+/// Raw image sent with the `image-data` hint.
+///
+/// See [Icons and Images](https://specifications.freedesktop.org/notification-spec/latest/ar01s05.html)
+#[derive(Clone, PartialEq)]
+pub struct ImageData {
+    /// width of the image in pixels
+    pub width: i32,
+    /// height of the image in pixels
+    pub height: i32,
+    /// distance in bytes between row starts
+    pub rowstride: i32,
+    /// whether the image has an alpha channel
+    pub has_alpha: bool,
+    /// number of bits per color sample
+    pub bits_per_sample: i32,
+    /// number of channels, 4 with alpha and 3 without
+    pub channels: i32,
+    /// pixel data
+    pub data: Vec<u8>,
+}
+
+impl fmt::Debug for ImageData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ImageData")
+            .field("width", &self.width)
+            .field("height", &self.height)
+            .field("rowstride", &self.rowstride)
+            .field("has_alpha", &self.has_alpha)
+            .field("bits_per_sample", &self.bits_per_sample)
+            .field("channels", &self.channels)
+            .field("data", &format_args!("[{} bytes]", self.data.len()))
+            .finish()
+    }
+}
+
+impl ImageData {
+    /// Decodes the `(iiibiiay)` struct of an image hint.
+    ///
+    /// Returns `None` if the value does not hold a valid image.
+    pub fn from_refarg(arg: &dyn RefArg) -> Option<Self> {
+        let mut fields = arg.as_iter()?;
+        let width = next_i32(&mut fields)?;
+        let height = next_i32(&mut fields)?;
+        let rowstride = next_i32(&mut fields)?;
+        let has_alpha = fields.next()?.as_u64()? != 0;
+        let bits_per_sample = next_i32(&mut fields)?;
+        let channels = next_i32(&mut fields)?;
+        let data = fields
+            .next()?
+            .as_iter()?
+            .map(|b| b.as_u64().and_then(|b| u8::try_from(b).ok()))
+            .collect::<Option<Vec<u8>>>()?;
+
+        let image = Self {
+            width,
+            height,
+            rowstride,
+            has_alpha,
+            bits_per_sample,
+            channels,
+            data,
+        };
+        image.is_valid().then_some(image)
+    }
+
+    /// Checks that the image is 8 bit RGB(A) and the data matches its dimensions.
+    fn is_valid(&self) -> bool {
+        let expected_channels = if self.has_alpha { 4 } else { 3 };
+        if self.width <= 0
+            || self.height <= 0
+            || self.width > MAX_IMAGE_DIMENSION
+            || self.height > MAX_IMAGE_DIMENSION
+            || self.bits_per_sample != 8
+            || self.channels != expected_channels
+            || self.rowstride < self.width * self.channels
+        {
+            return false;
+        }
+        let required = self.rowstride as usize * (self.height as usize - 1)
+            + self.width as usize * self.channels as usize;
+        self.data.len() >= required
+    }
+
+    /// Returns a stable hash of the image content.
+    pub fn content_hash(&self) -> u64 {
+        let mut hash = Fnv1a::default();
+        for field in [
+            self.width,
+            self.height,
+            self.rowstride,
+            self.has_alpha as i32,
+            self.bits_per_sample,
+            self.channels,
+        ] {
+            hash.write(&field.to_le_bytes());
+        }
+        hash.write(&self.data);
+        hash.0
+    }
+
+    /// Encodes the image as compressed PNG.
+    pub fn to_png(&self) -> io::Result<Vec<u8>> {
+        let row_length = self.width as usize * self.channels as usize;
+        let mut pixels = Vec::with_capacity(row_length * self.height as usize);
+        for row in self.data.chunks(self.rowstride as usize).take(self.height as usize) {
+            pixels.extend_from_slice(&row[..row_length]);
+        }
+
+        let mut png = Vec::new();
+        let mut encoder = png::Encoder::new(&mut png, self.width as u32, self.height as u32);
+        encoder.set_color(if self.has_alpha { png::ColorType::Rgba } else { png::ColorType::Rgb });
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header()?;
+        writer.write_image_data(&pixels)?;
+        writer.finish()?;
+        Ok(png)
+    }
+}
+
+/// Reads the next struct field as `i32`.
+fn next_i32<'a>(fields: &mut impl Iterator<Item = &'a dyn RefArg>) -> Option<i32> {
+    fields
+        .next()
+        .and_then(|f| f.as_i64())
+        .and_then(|v| i32::try_from(v).ok())
+}
+
+/// Writes images to a size bounded cache directory, named by their content.
+#[derive(Debug)]
+pub struct ImageCache {
+    dir: PathBuf,
+    max_size: u64,
+}
+
+impl ImageCache {
+    /// Creates a cache in the given directory, holding at most `max_size` bytes.
+    pub fn new(dir: PathBuf, max_size: u64) -> Self {
+        Self { dir, max_size }
+    }
+
+    /// Returns the default cache directory, `$XDG_CACHE_HOME/armesto/images`.
+    pub fn default_dir() -> PathBuf {
+        xdg::cache_home().join("armesto").join("images")
+    }
+
+    /// Stores the image as PNG and returns its path.
+    ///
+    /// Evicts the oldest images if the cache grows beyond its size bound.
+    pub fn store(&self, image: &ImageData) -> io::Result<PathBuf> {
+        let path = self.dir.join(format!("{:016x}.png", image.content_hash()));
+        if !path.exists() {
+            fs::create_dir_all(&self.dir)?;
+            fs::write(&path, image.to_png()?)?;
+            debug!("cached image at {:?}", path);
+            self.evict(&path)?;
+        }
+        Ok(path)
+    }
+
+    /// Removes the cached images that are not used by any notification in the store,
+    /// its history or its queue of held back notifications.
+    pub fn prune(&self, db: &NotificationStore) {
+        let referenced: HashSet<PathBuf> = db
+            .items()
+            .into_iter()
+            .chain(db.history())
+            .chain(db.queued())
+            .filter_map(|n| n.image)
+            .collect();
+        let entries = match self.entries() {
+            Ok(entries) => entries,
+            Err(_) => return,
+        };
+        for (path, _, _) in entries {
+            if !referenced.contains(&path) {
+                debug!("removing unused image {:?}", path);
+                if let Err(e) = fs::remove_file(&path) {
+                    warn!("unable to remove cached image {:?}: {}", path, e);
+                }
+            }
+        }
+    }
+
+    /// Removes the oldest images until the cache fits its size bound, keeping `keep`.
+    fn evict(&self, keep: &Path) -> io::Result<()> {
+        let mut entries = self.entries()?;
+        let mut size: u64 = entries.iter().map(|(_, len, _)| len).sum();
+        entries.sort_by_key(|(_, _, modified)| *modified);
+
+        for (path, len, _) in entries {
+            if size <= self.max_size {
+                break;
+            }
+            if path != keep {
+                fs::remove_file(&path)?;
+                size = size.saturating_sub(len);
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the cached images with their size and modification time.
+    fn entries(&self) -> io::Result<Vec<(PathBuf, u64, std::time::SystemTime)>> {
+        let mut entries = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let path = entry.path();
+            if path.extension().map_or(false, |ext| ext == "png") {
+                let metadata = entry.metadata()?;
+                entries.push((path, metadata.len(), metadata.modified()?));
+            }
+        }
+        Ok(entries)
+    }
+}
+
+/// 64 bit FNV-1a hash, stable across builds.
+struct Fnv1a(u64);
+
+impl Default for Fnv1a {
+    fn default() -> Self {
+        Self(0xcbf2_9ce4_8422_2325)
+    }
+}
+
+impl Fnv1a {
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= u64::from(*byte);
+            self.0 = self.0.wrapping_mul(0x0000_0100_0000_01b3);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notification::Notification;
+    use std::collections::VecDeque;
+
+    #[test]
+    fn image_from_refarg() {
+        let image = ImageData::from_refarg(&image_struct(2, 1, 8, vec![255; 8]))
+            .expect("Decodes valid image");
+
+        assert_eq!(image.width, 2);
+        assert_eq!(image.height, 1);
+        assert!(image.has_alpha);
+        assert_eq!(image.data.len(), 8);
+
+        assert!(
+            ImageData::from_refarg(&image_struct(2, 1, 8, vec![255; 7])).is_none(),
+            "data shorter than the image is rejected"
+        );
+        assert!(
+            ImageData::from_refarg(&image_struct(2, 1, 4, vec![255; 8])).is_none(),
+            "rowstride shorter than a row is rejected"
+        );
+        assert!(ImageData::from_refarg(&String::from("not an image")).is_none());
+    }
+
+    #[test]
+    fn image_to_png() {
+        let image = ImageData {
+            width: 1,
+            height: 2,
+            rowstride: 4,
+            has_alpha: false,
+            bits_per_sample: 8,
+            channels: 3,
+            data: vec![1, 2, 3, 0, 4, 5, 6, 0],
+        };
+
+        let png = image.to_png().expect("Encodes image");
+
+        let mut reader = png::Decoder::new(png.as_slice()).read_info().expect("Decodes header");
+        let mut pixels = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut pixels).expect("Decodes image");
+        assert_eq!((info.width, info.height), (1, 2));
+        assert_eq!(info.color_type, png::ColorType::Rgb);
+        assert_eq!(pixels, vec![1, 2, 3, 4, 5, 6], "rowstride padding is dropped");
+
+        let avatar = ImageData {
+            width: 256,
+            height: 256,
+            rowstride: 256 * 4,
+            has_alpha: true,
+            bits_per_sample: 8,
+            channels: 4,
+            data: vec![128; 256 * 256 * 4],
+        };
+        let png = avatar.to_png().expect("Encodes image");
+        assert!(png.len() < avatar.data.len() / 10, "compressed, got {} bytes", png.len());
+    }
+
+    #[test]
+    fn image_cache_store_and_prune() {
+        let dir = std::env::temp_dir().join(format!("armesto-image-test-{}", std::process::id()));
+        let unit = ImageCache::new(dir.clone(), 1024 * 1024);
+        let image = ImageData::from_refarg(&image_struct(2, 1, 8, vec![255; 8]))
+            .expect("Decodes valid image");
+
+        let path = unit.store(&image).expect("Stores image");
+        assert!(path.exists());
+        assert_eq!(unit.store(&image).expect("Stores image"), path, "keyed by content");
+
+        let db = NotificationStore::init();
+        db.add(Notification {
+            id: 1,
+            image: Some(path.clone()),
+            ..Default::default()
+        });
+        unit.prune(&db);
+        assert!(path.exists(), "referenced image is kept");
+
+        db.delete(1);
+        unit.prune(&db);
+        assert!(path.exists(), "image in history is kept");
+
+        let db = NotificationStore::init();
+        unit.prune(&db);
+        assert!(!path.exists(), "unreferenced image is removed");
+
+        fs::remove_dir_all(dir).expect("Removes test directory");
+    }
+
+    #[test]
+    fn image_cache_keeps_held_back_images() {
+        let dir = std::env::temp_dir().join(format!("armesto-image-queue-test-{}", std::process::id()));
+        let unit = ImageCache::new(dir.clone(), 1024 * 1024);
+        let db = NotificationStore::init();
+        db.set_paused(true);
+
+        let image = ImageData::from_refarg(&image_struct(2, 1, 8, vec![255; 8]))
+            .expect("Decodes valid image");
+        let path = unit.store(&image).expect("Stores image");
+        db.enqueue(Notification {
+            id: 1,
+            image: Some(path.clone()),
+            ..Default::default()
+        });
+        unit.prune(&db);
+
+        assert_eq!(db.set_paused(false), vec![(1, false)]);
+        assert_eq!(db.get(1).and_then(|n| n.image), Some(path.clone()));
+        assert!(path.exists(), "image of the resumed notification is kept");
+
+        fs::remove_dir_all(dir).expect("Removes test directory");
+    }
+
+    fn image_struct(width: i32, height: i32, rowstride: i32, data: Vec<u8>) -> VecDeque<Box<dyn RefArg>> {
+        VecDeque::from(vec![
+            Box::new(width) as Box<dyn RefArg>,
+            Box::new(height),
+            Box::new(rowstride),
+            Box::new(true),
+            Box::new(8_i32),
+            Box::new(4_i32),
+            Box::new(data),
+        ])
+    }
+}
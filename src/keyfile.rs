@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+
+/// Groups of key/value entries, as found in `index.theme` and `.desktop` files.
+///
+/// See [Basic format of the file](https://specifications.freedesktop.org/desktop-entry-spec/latest/ar01s03.html)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct KeyFile {
+    groups: HashMap<String, HashMap<String, String>>,
+}
+
+impl KeyFile {
+    /// Parses the content of a key file.
+    ///
+    /// Comments, blank lines and entries outside of a group are ignored.
+    /// The first occurrence of a group or key wins.
+    pub fn parse(content: &str) -> Self {
+        let mut groups: HashMap<String, HashMap<String, String>> = HashMap::new();
+        let mut current: Option<String> = None;
+
+        for line in content.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(group) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                current = if groups.contains_key(group) {
+                    None
+                } else {
+                    groups.insert(group.to_string(), HashMap::new());
+                    Some(group.to_string())
+                };
+                continue;
+            }
+            if let (Some(group), Some((key, value))) = (&current, line.split_once('=')) {
+                if let Some(entries) = groups.get_mut(group) {
+                    entries
+                        .entry(key.trim().to_string())
+                        .or_insert_with(|| value.trim().to_string());
+                }
+            }
+        }
+
+        Self { groups }
+    }
+
+    /// Returns the names of all groups.
+    pub fn groups(&self) -> impl Iterator<Item = &str> {
+        self.groups.keys().map(String::as_str)
+    }
+
+    /// Returns the value of a key in a group.
+    pub fn get(&self, group: &str, key: &str) -> Option<&str> {
+        self.groups
+            .get(group)
+            .and_then(|entries| entries.get(key))
+            .map(String::as_str)
+    }
+
+    /// Returns the value of a key as a list, separated by `separator`.
+    pub fn get_list(&self, group: &str, key: &str, separator: char) -> Vec<&str> {
+        self.get(group, key)
+            .map(|value| {
+                value
+                    .split(separator)
+                    .map(str::trim)
+                    .filter(|item| !item.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns the value of a key as a number.
+    pub fn get_u32(&self, group: &str, key: &str) -> Option<u32> {
+        self.get(group, key).and_then(|value| value.parse().ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keyfile_parse() {
+        let unit = KeyFile::parse(
+            "# comment\nignored=1\n[Icon Theme]\nName = Test\nInherits=a,b,\n\n[16x16/apps]\nSize=16\n[Icon Theme]\nName=Duplicate\n",
+        );
+
+        assert_eq!(unit.get("Icon Theme", "Name"), Some("Test"));
+        assert_eq!(unit.get_list("Icon Theme", "Inherits", ','), vec!["a", "b"]);
+        assert_eq!(unit.get_u32("16x16/apps", "Size"), Some(16));
+        assert_eq!(unit.get("Icon Theme", "ignored"), None);
+        assert_eq!(unit.get("missing", "Name"), None);
+        assert_eq!(unit.groups().count(), 2);
+    }
+}
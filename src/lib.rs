@@ -11,83 +11,537 @@ pub mod dbus;
 /// Notification manager.
 pub mod notification;
 
+/// Notification hints.
+pub mod hint;
+
+/// Notification images.
+pub mod image;
+
+/// Icon theme lookup.
+pub mod icon;
+
+/// Desktop entry lookup.
+pub mod desktop;
+
+/// Body markup.
+pub mod markup;
+
+/// Key file parser.
+pub mod keyfile;
+
+/// XDG base directories.
+pub mod xdg;
+
+/// Notification expiry.
+pub mod expiry;
+
+/// Persistent state.
+pub mod state;
+
+/// Configuration file.
+pub mod config;
+
+/// Notification rules.
+pub mod rules;
+
+/// Notification templates.
+pub mod template;
+
 /// Rofi server
 pub mod rofi;
 
-use crate::dbus::DbusServer;
-use crate::error::Result;
+use crate::dbus::{DbusServer, Signal, SignalSender};
+use crate::error::{Error, Result};
+use crate::expiry::{ExpiryScheduler, ExpiryTimeouts};
+use crate::desktop::DesktopEntries;
+use crate::icon::IconResolver;
+use crate::image::ImageCache;
 use clap::Parser;
-use log::{debug};
-use notification::Action;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use log::{debug, error, warn, LevelFilter};
+use notification::{Action, CloseReason, Urgency};
 use crate::rofi::RofiServer;
-use notification::NotificationStore;
-use std::sync::mpsc;
+use crate::rules::Rule;
+use crate::template::{Templates, TimeZone};
+use crate::state::{State, StateFile};
+use notification::{NotificationStore, DEFAULT_HISTORY_SIZE};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::mpsc::{self, RecvError, RecvTimeoutError};
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-/// Startup configuration 
+/// Minimum time between two saves of the state file.
+const SAVE_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Startup configuration
+///
+/// Values are layered: defaults, then the configuration file, then the
+/// environment, then the command line.
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 pub struct Config {
-    /// Local path to file representing domain socket
-    #[arg(short, long, default_value = "/tmp/armesto")]
-    pub socket_path: String,
+    /// Task to run instead of the daemon
+    #[command(subcommand)]
+    pub task: Option<Task>,
+
+    /// Configuration file [default: $XDG_CONFIG_HOME/armesto/config.toml]
+    #[arg(short, long, env = "ARMESTO_CONFIG")]
+    pub config: Option<PathBuf>,
+
+    /// Maximum level of the messages sent to syslog
+    #[arg(long, default_value_t = LevelFilter::Debug, env = "ARMESTO_LOG_LEVEL")]
+    pub log_level: LevelFilter,
+
+    /// Local path to file representing domain socket [default: $XDG_RUNTIME_DIR/rofi_notification_daemon]
+    #[arg(short, long, env = "ARMESTO_SOCKET_PATH")]
+    pub socket_path: Option<PathBuf>,
 
     /// Duration to wait for incoming d-bus messages
-    #[arg(short, long, default_value_t = 1000)]
+    #[arg(short, long, default_value_t = 1000, env = "ARMESTO_DBUS_POLL_TIMEOUT")]
     pub dbus_poll_timeout: u16,
+
+    /// Default expiration timeout in milliseconds for low urgency notifications, 0 to never expire
+    #[arg(long, default_value_t = 10000, env = "ARMESTO_LOW_TIMEOUT")]
+    pub low_timeout: u32,
+
+    /// Default expiration timeout in milliseconds for normal urgency notifications, 0 to never expire
+    #[arg(long, default_value_t = 10000, env = "ARMESTO_NORMAL_TIMEOUT")]
+    pub normal_timeout: u32,
+
+    /// Default expiration timeout in milliseconds for critical notifications, 0 to never expire
+    #[arg(long, default_value_t = 0, env = "ARMESTO_CRITICAL_TIMEOUT")]
+    pub critical_timeout: u32,
+
+    /// Let critical notifications expire instead of keeping them until dismissed
+    #[arg(long, env = "ARMESTO_EXPIRE_CRITICAL")]
+    pub expire_critical: bool,
+
+    /// List replaced notifications as the newest instead of keeping their position
+    #[arg(long, env = "ARMESTO_MOVE_REPLACED")]
+    pub move_replaced: bool,
+
+    /// Maximum size of the notification image cache in kilobytes
+    #[arg(long, default_value_t = 16384, env = "ARMESTO_IMAGE_CACHE_SIZE")]
+    pub image_cache_size: u64,
+
+    /// Icon theme used to resolve icon names
+    #[arg(long, default_value = "hicolor", env = "ARMESTO_ICON_THEME")]
+    pub icon_theme: String,
+
+    /// Preferred size of resolved icons in pixels
+    #[arg(long, default_value_t = 32, env = "ARMESTO_ICON_SIZE")]
+    pub icon_size: u32,
+
+    /// Command used to open links, the link is passed as last argument
+    #[arg(long, default_value = "xdg-open", env = "ARMESTO_LINK_OPENER")]
+    pub link_opener: String,
+
+    /// Number of dismissed notifications that can be restored
+    #[arg(long, default_value_t = DEFAULT_HISTORY_SIZE, env = "ARMESTO_HISTORY_SIZE")]
+    pub history_size: usize,
+
+    /// Time in milliseconds after which idle rofication clients are disconnected
+    #[arg(long, default_value_t = 5000, env = "ARMESTO_SOCKET_TIMEOUT")]
+    pub socket_timeout: u64,
+
+    /// Reply to rofication clients like stock rofication: one command per connection, bare data, no acknowledgements or errors
+    #[arg(long, env = "ARMESTO_LEGACY_PROTOCOL")]
+    pub legacy_protocol: bool,
+
+    /// File keeping the notifications across restarts [default: $XDG_STATE_HOME/armesto/state.json]
+    #[arg(long, env = "ARMESTO_STATE_FILE")]
+    pub state_file: Option<PathBuf>,
+
+    /// Hold back critical notifications while paused instead of delivering them
+    #[arg(long, env = "ARMESTO_QUEUE_CRITICAL")]
+    pub queue_critical: bool,
+
+    /// Template of the lines returned by the 'render' command of the socket
+    #[arg(long, default_value = template::DEFAULT_TEMPLATE, env = "ARMESTO_TEMPLATE")]
+    pub template: String,
+
+    /// Templates selected by name in the 'render' command, only set in the configuration file
+    #[arg(skip)]
+    pub templates: BTreeMap<String, String>,
+
+    /// Rules applied to incoming notifications, only set in the configuration file
+    #[arg(skip)]
+    pub rules: Vec<Rule>,
+}
+
+/// Tasks run instead of the daemon.
+#[derive(clap::Subcommand, Debug, Clone, PartialEq)]
+pub enum Task {
+    /// Validate the configuration file and report errors with line numbers
+    CheckConfig,
+}
+
+impl Config {
+    /// Path of the rofication socket
+    pub fn socket_path(&self) -> PathBuf {
+        self.socket_path.clone().unwrap_or_else(rofi::default_socket_path)
+    }
+
+    /// Path of the state file
+    pub fn state_file(&self) -> PathBuf {
+        self.state_file.clone().unwrap_or_else(StateFile::default_path)
+    }
+
+    /// Default expiration timeouts
+    pub fn expiry_timeouts(&self) -> ExpiryTimeouts {
+        ExpiryTimeouts {
+            low: self.low_timeout,
+            normal: self.normal_timeout,
+            critical: self.critical_timeout,
+            sticky_critical: !self.expire_critical,
+        }
+    }
 }
 
 /// Service entry-point
 pub fn run(config: Config) -> Result<()> {
+    // SAFETY: no thread is started yet.
+    unsafe { template::load_local_time_zone() };
+    let templates = Templates::new(&config.template, &config.templates, TimeZone::Local)?;
     let dbus_server = DbusServer::init()?;
-    let db = NotificationStore::init();
+    let db = NotificationStore::init_with_history(config.history_size);
     let (dbus_sender, receiver) = mpsc::channel();
-    let rofi_sender = dbus_sender.clone();
+    let (signal_sender, signal_receiver) = dbus::signal_channel()?;
+    let mut expiry = ExpiryScheduler::new(config.expiry_timeouts());
+    let move_replaced = config.move_replaced;
+    let images = ImageCache::new(ImageCache::default_dir(), config.image_cache_size * 1024);
+    let mut icons = IconResolver::new(config.icon_theme.clone(), config.icon_size);
+    let mut desktop_entries = DesktopEntries::new();
+    let link_opener = config.link_opener.clone();
+    let queue_critical = config.queue_critical;
+    let rules = config.rules.clone();
+    let state_file = StateFile::new(config.state_file());
+    if let Some(state) = state_file.load() {
+        dbus::set_next_id(state.next_id());
+        state.apply(&db);
+        debug!("restored {} notifications", db.count());
+        for notification in db.items() {
+            expiry.schedule(&notification, Instant::now());
+        }
+    }
+    let mut saved_revision = db.revision();
+    let rofi_server = RofiServer::new(
+        config.socket_path(),
+        db.clone(),
+        dbus_sender.clone(),
+        Duration::from_millis(config.socket_timeout),
+        config.legacy_protocol,
+        templates,
+    );
+    // The socket is removed when the daemon shuts down.
+    let (listener, _socket) = rofi_server.bind()?;
 
+    let signal_action_sender = dbus_sender.clone();
+    let db_clone = db.clone();
     thread::Builder::new().name("dbus".to_string()).spawn(move || {
         debug!("registering D-Bus server");
         let dbus_sender2 = dbus_sender.clone();
         let duration = Duration::from_millis(config.dbus_poll_timeout.into());
-        dbus_server
-            .register_notification_handler(dbus_sender, duration)
-            .unwrap_or_else(|err| {
-                dbus_sender2.send(Action::Shutdown(err.into())).unwrap();
-                ()
-            });
+        if let Err(err) = dbus_server.register_notification_handler(
+            dbus_sender,
+            signal_receiver,
+            db_clone,
+            duration,
+        ) {
+            if dbus_sender2.send(Action::Shutdown(err)).is_err() {
+                error!("D-Bus server failed and main loop is gone");
+            }
+        }
+    })?;
+
+    // Stopping the daemon goes through the main loop, so the socket is removed.
+    let mut signals = Signals::new([SIGTERM, SIGINT, SIGHUP])?;
+    thread::Builder::new().name("signals".to_string()).spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            if signal_action_sender.send(Action::Shutdown(Error::Signal(signal))).is_err() {
+                error!("received signal {} and main loop is gone", signal);
+            }
+        }
     })?;
 
-    let db_clone = db.clone();
     thread::Builder::new().name("rofication".to_string()).spawn(move || {
         debug!("starting rofication server");
-        let rofi_server = RofiServer::new("/tmp/rofi_notification_daemon".to_string(), db_clone);
-        rofi_server
-            .start()
-            .unwrap_or_else(|err| {
-                rofi_sender.send(Action::Shutdown(err.into())).unwrap();
-                ()
-            });
+        rofi_server.start(listener);
     })?;
 
-    loop {
-        match receiver.recv()? {
-            Action::Show(notification) => {                
-                db.add(notification);
+    let mut saved_at: Option<Instant> = None;
+    let result = loop {
+        // Saves are spaced out, e.g. for progress bars updated several times a second.
+        let now = Instant::now();
+        let save_delay = if db.revision() == saved_revision {
+            None
+        } else {
+            let delay = saved_at.map_or(Duration::ZERO, |at| {
+                (at + SAVE_INTERVAL).saturating_duration_since(now)
+            });
+            if delay.is_zero() {
+                saved_revision = db.revision();
+                saved_at = Some(now);
+                save_state(&state_file, &db);
+                None
+            } else {
+                Some(delay)
             }
-            Action::ShowLast => {
-                debug!("showing the last notification");
+        };
+
+        let timeout = [expiry.next_timeout(now), save_delay].into_iter().flatten().min();
+        let action = match timeout {
+            Some(timeout) => match receiver.recv_timeout(timeout) {
+                Ok(action) => Some(action),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break Err(RecvError.into()),
+            },
+            None => match receiver.recv() {
+                Ok(action) => Some(action),
+                Err(e) => break Err(e.into()),
+            },
+        };
+
+        let expired = expiry.expired(Instant::now());
+        for id in &expired {
+            debug!("notification {} expired", id);
+            if db.delete(*id) {
+                emit_closed(&signal_sender, vec![*id], CloseReason::Expired);
+            }
+        }
+        if !expired.is_empty() {
+            images.prune(&db);
+        }
+
+        let action = match action {
+            Some(action) => action,
+            None => continue,
+        };
+
+        // Images only become unused when notifications are removed or replaced.
+        let mut removed = false;
+        match action {
+            Action::Show(mut notification, reply) => {
+                // Per the spec, an unknown `replaces_id` is treated like a new notification.
+                if notification.id == 0 || !db.contains(notification.id) {
+                    notification.id = dbus::issue_id();
+                }
+                let id = notification.id;
+                if reply.send(id).is_err() {
+                    warn!("unable to reply with the id of notification {}", id);
+                }
+                let mut notification = match rules::apply(&rules, *notification) {
+                    Some(notification) => Box::new(notification),
+                    None => {
+                        debug!("dropped notification by rule: {}", id);
+                        // The sender got an id, let it know the notification is gone.
+                        if !db.contains(id) {
+                            emit(&signal_sender, Signal::NotificationClosed(id, CloseReason::Undefined));
+                        }
+                        continue;
+                    }
+                };
+                if let Some(image) = notification.image_data.take() {
+                    match images.store(&image) {
+                        Ok(path) => notification.image = Some(path),
+                        Err(e) => warn!("unable to cache image of notification {}: {}", notification.id, e),
+                    }
+                }
+                notification.render_body();
+                let mut icon = notification.icon.clone();
+                if let Some(entry) = notification
+                    .desktop_entry
+                    .as_deref()
+                    .and_then(|id| desktop_entries.lookup(id))
+                {
+                    notification.app_name = entry.name;
+                    if icon.is_empty() {
+                        icon = entry.icon.unwrap_or_default();
+                    }
+                }
+                notification.icon_path = icons.resolve(&icon);
+                removed = db.contains(id);
+                let bypasses_pause = notification.urgency == Urgency::Critical && !queue_critical;
+                if db.is_paused() && !bypasses_pause && !db.is_delivered(id) {
+                    debug!("holding back notification while paused: {}", id);
+                    db.enqueue(*notification);
+                } else {
+                    expiry.schedule(&notification, Instant::now());
+                    let signal = if db.upsert(*notification, move_replaced) {
+                        debug!("replaced notification: {}", id);
+                        Signal::NotificationReplaced(id)
+                    } else {
+                        Signal::NotificationAdded(id)
+                    };
+                    emit(&signal_sender, signal);
+                }
+            }
+            Action::ShowLast(reply) => {
+                let id = db.restore();
+                debug!("restored the last notification: {:?}", id);
+                if let Some(notification) = id.and_then(|id| db.get(id)) {
+                    // The restored notification expires like a new one.
+                    expiry.schedule(&notification, Instant::now());
+                    emit(&signal_sender, Signal::NotificationAdded(notification.id));
+                }
+                if reply.send(id).is_err() {
+                    warn!("unable to reply with the restored notification");
+                }
             }
             Action::Close(id) => {
+                debug!("closing notification: {}", id);
+                expiry.cancel(id);
+                removed = db.delete(id);
+                if removed {
+                    emit_closed(&signal_sender, vec![id], CloseReason::Closed);
+                }
+            }
+            Action::CloseLast(reply) => {
+                let id = db.delete_newest();
+                debug!("closed the newest notification: {:?}", id);
                 if let Some(id) = id {
-                    debug!("closing notification: {}", id);
-                    db.delete(id);
-                } 
+                    expiry.cancel(id);
+                    emit_closed(&signal_sender, vec![id], CloseReason::Dismissed);
+                    removed = true;
+                }
+                if reply.send(id).is_err() {
+                    warn!("unable to reply with the closed notification");
+                }
             }
             Action::CloseAll => {
                 debug!("closing all notifications");
-                db.delete_all();
+                let ids = db.delete_all();
+                removed = !ids.is_empty();
+                ids.iter().for_each(|id| expiry.cancel(*id));
+                emit_closed(&signal_sender, ids, CloseReason::Dismissed);
+            }
+            Action::Closed(ids, reason) => {
+                removed = true;
+                ids.iter().for_each(|id| expiry.cancel(*id));
+                emit_closed(&signal_sender, ids, reason);
+            }
+            Action::Invoke(id, key) => {
+                match db.get(id) {
+                    Some(notification) if notification.has_action(&key) => {
+                        debug!("invoking action '{}' of notification {}", key, id);
+                        emit(&signal_sender, Signal::ActionInvoked(id, key));
+                        if !notification.resident && db.delete(id) {
+                            removed = true;
+                            expiry.cancel(id);
+                            emit_closed(&signal_sender, vec![id], CloseReason::Dismissed);
+                        }
+                    }
+                    _ => warn!("notification {} has no action '{}'", id, key),
+                }
+            }
+            Action::OpenLink(id, index) => {
+                match db.get(id).and_then(|n| n.links.get(index).cloned()) {
+                    Some(link) => open_link(&link_opener, &link),
+                    None => warn!("notification {} has no link {}", id, index),
+                }
+            }
+            Action::Seen(id) => {
+                if let Some(notification) = db.get(id) {
+                    emit(
+                        &signal_sender,
+                        Signal::NotificationStateChanged {
+                            id,
+                            seen: notification.seen,
+                            urgency: notification.urgency,
+                        },
+                    );
+                }
+            }
+            Action::Pause(paused, reply) => {
+                let paused = paused.unwrap_or(!db.is_paused());
+                debug!("paused: {}", paused);
+                for (id, replaced) in db.set_paused(paused) {
+                    removed |= replaced;
+                    if let Some(notification) = db.get(id) {
+                        expiry.schedule(&notification, Instant::now());
+                        let signal = if replaced {
+                            Signal::NotificationReplaced(id)
+                        } else {
+                            Signal::NotificationAdded(id)
+                        };
+                        emit(&signal_sender, signal);
+                    }
+                }
+                if reply.send(paused).is_err() {
+                    warn!("unable to reply with the paused state");
+                }
+            }
+            Action::Shutdown(Error::Signal(signal)) => {
+                debug!("shutting down on signal {}", signal);
+                break Ok(());
             }
             Action::Shutdown(reason) => break Err(reason),
         }
+
+        if removed {
+            images.prune(&db);
+        }
+        // Lets the D-Bus thread emit the changed properties right away.
+        signal_sender.wake();
+    };
+
+    if db.revision() != saved_revision {
+        save_state(&state_file, &db);
+    }
+    result
+}
+
+/// Saves the content of the store, logging failures.
+fn save_state(state_file: &StateFile, db: &NotificationStore) {
+    if let Err(e) = state_file.save(&State::capture(db, dbus::next_id())) {
+        warn!("unable to save state to {}: {}", state_file.path().display(), e);
+    }
+}
+
+/// Opens a link with the configured opener, without waiting for it in the main loop.
+fn open_link(opener: &str, link: &str) {
+    // Links restored from the state file were not necessarily checked.
+    if !markup::is_safe_link(link) {
+        warn!("refusing to open link {}", link);
+        return;
+    }
+    let mut words = opener.split_whitespace();
+    let program = match words.next() {
+        Some(program) => program,
+        None => {
+            warn!("no link opener configured");
+            return;
+        }
+    };
+
+    debug!("opening link {} with {}", link, opener);
+    match Command::new(program).args(words).arg(link).spawn() {
+        Ok(mut child) => {
+            let reaper = thread::Builder::new()
+                .name("link-opener".to_string())
+                .spawn(move || child.wait());
+            if let Err(e) = reaper {
+                warn!("unable to wait for link opener: {}", e);
+            }
+        }
+        Err(e) => error!("unable to run link opener '{}': {}", opener, e),
+    }
+}
+
+/// Queues the `NotificationClosed` and `NotificationRemoved` signals for each of the given notifications.
+fn emit_closed(signal_sender: &SignalSender, ids: Vec<u32>, reason: CloseReason) {
+    for id in ids {
+        emit(signal_sender, Signal::NotificationClosed(id, reason));
+        emit(signal_sender, Signal::NotificationRemoved(id, reason));
+    }
+}
+
+/// Queues a signal for the D-Bus thread.
+fn emit(signal_sender: &SignalSender, signal: Signal) {
+    if let Err(e) = signal_sender.send(signal) {
+        error!("unable to queue signal {:?}", e.0);
     }
 }
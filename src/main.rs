@@ -1,10 +1,22 @@
 use std::{process};
-use armesto::Config;
-use clap::{Parser};
+use armesto::config::ConfigFile;
+use armesto::{Config, Task};
 use syslog::{Facility, Formatter3164, BasicLogger};
-use log::{error, LevelFilter, debug};
+use log::{error, debug};
 
 fn main() {
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(1)
+        }
+    };
+
+    if let Some(Task::CheckConfig) = config.task {
+        process::exit(check_config(&config));
+    }
+
     let formatter = Formatter3164 {
         facility: Facility::LOG_USER,
         hostname: None,
@@ -18,10 +30,9 @@ fn main() {
     };
 
     log::set_boxed_logger(Box::new(BasicLogger::new(logger)))
-        .map(|()| log::set_max_level(LevelFilter::Debug))
+        .map(|()| log::set_max_level(config.log_level))
         .expect("can set logger");    
 
-    let config = Config::parse();
     debug!("Starting armesto with {:?}", config);
 
     match armesto::run(config) {
@@ -32,3 +43,26 @@ fn main() {
         }
     }
 }
+
+/// Validates the configuration file, returning the exit code.
+fn check_config(config: &Config) -> i32 {
+    let path = config.config_path();
+    match ConfigFile::read(&path, config.config.is_some()) {
+        Ok(Some(_)) => {
+            println!("{}: ok", path.display());
+            0
+        }
+        Ok(None) => {
+            println!("{}: not found, using the defaults", path.display());
+            0
+        }
+        Err(armesto::error::Error::Config(message)) => {
+            eprintln!("{}", message);
+            1
+        }
+        Err(e) => {
+            eprintln!("{}", e);
+            1
+        }
+    }
+}
@@ -0,0 +1,382 @@
+/// Formatting tags supported in notification bodies.
+///
+/// See [Markup](https://specifications.freedesktop.org/notification-spec/latest/ar01s04.html)
+#[derive(Clone, Debug, PartialEq)]
+pub enum Tag {
+    /// `<b>`
+    Bold,
+    /// `<i>`
+    Italic,
+    /// `<u>`
+    Underline,
+    /// `<a href="...">`
+    Link(String),
+}
+
+impl Tag {
+    fn name(&self) -> &'static str {
+        match self {
+            Tag::Bold => "b",
+            Tag::Italic => "i",
+            Tag::Underline => "u",
+            Tag::Link(_) => "a",
+        }
+    }
+
+    /// Tag used to render the formatting with Pango, which has no links.
+    fn pango_name(&self) -> &'static str {
+        match self {
+            Tag::Link(_) => "u",
+            tag => tag.name(),
+        }
+    }
+}
+
+/// Element of a parsed body.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Node {
+    /// unescaped text
+    Text(String),
+    /// start of a formatted section
+    Start(Tag),
+    /// end of the innermost formatted section
+    End(Tag),
+    /// `<img src="..." alt="...">`
+    Image {
+        /// path or URI of the image
+        src: String,
+        /// alternative text of the image
+        alt: String,
+    },
+}
+
+/// Notification body, parsed from the markup subset of the spec.
+///
+/// Unsupported tags are dropped, stray `<` and `&` are kept as text and
+/// tags are always balanced, so the renderings are safe to display.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Markup {
+    nodes: Vec<Node>,
+}
+
+impl Markup {
+    /// Parses a notification body.
+    pub fn parse(input: &str) -> Self {
+        let mut nodes = Vec::new();
+        let mut open: Vec<Tag> = Vec::new();
+        let mut text = String::new();
+        let mut rest = input;
+
+        while let Some(start) = rest.find('<') {
+            text.push_str(&decode_entities(&rest[..start]));
+            let after = &rest[start + 1..];
+            let element = after
+                .find('>')
+                .map(|end| (&after[..end], &after[end + 1..]))
+                .filter(|(content, _)| is_element(content));
+
+            match element {
+                Some((content, remainder)) => {
+                    if let Some(node) = parse_element(content, &mut open) {
+                        if !text.is_empty() {
+                            nodes.push(Node::Text(std::mem::take(&mut text)));
+                        }
+                        nodes.push(node);
+                    }
+                    rest = remainder;
+                }
+                None => {
+                    text.push('<');
+                    rest = after;
+                }
+            }
+        }
+        text.push_str(&decode_entities(rest));
+        if !text.is_empty() {
+            nodes.push(Node::Text(text));
+        }
+        nodes.extend(open.into_iter().rev().map(Node::End));
+
+        Self { nodes }
+    }
+
+    /// Returns the parsed elements.
+    pub fn nodes(&self) -> &[Node] {
+        &self.nodes
+    }
+
+    /// Returns the targets of links and the URLs found in the text, without duplicates.
+    ///
+    /// Only web and mail links are returned, see [`is_safe_link`].
+    pub fn links(&self) -> Vec<String> {
+        let mut links: Vec<String> = Vec::new();
+        for node in &self.nodes {
+            let found = match node {
+                Node::Start(Tag::Link(href)) if is_safe_link(href) => vec![href.as_str()],
+                Node::Text(text) => find_urls(text),
+                _ => Vec::new(),
+            };
+            for link in found {
+                if !links.iter().any(|l| l == link) {
+                    links.push(link.to_string());
+                }
+            }
+        }
+        links
+    }
+
+    /// Renders the body as plain text, replacing images by their alternative text.
+    pub fn to_plain(&self) -> String {
+        self.nodes
+            .iter()
+            .map(|node| match node {
+                Node::Text(text) => text.as_str(),
+                Node::Image { alt, .. } => alt.as_str(),
+                Node::Start(_) | Node::End(_) => "",
+            })
+            .collect()
+    }
+
+    /// Renders the body as Pango markup, e.g. for rofi.
+    ///
+    /// Links are underlined and images are replaced by their alternative text.
+    pub fn to_pango(&self) -> String {
+        let mut out = String::new();
+        for node in &self.nodes {
+            match node {
+                Node::Text(text) => out.push_str(&escape(text)),
+                Node::Image { alt, .. } => out.push_str(&escape(alt)),
+                Node::Start(tag) => {
+                    out.push('<');
+                    out.push_str(tag.pango_name());
+                    out.push('>');
+                }
+                Node::End(tag) => {
+                    out.push_str("</");
+                    out.push_str(tag.pango_name());
+                    out.push('>');
+                }
+            }
+        }
+        out
+    }
+}
+
+/// Escapes text for use in XML based markup.
+pub fn escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => out.push_str("&amp;"),
+            '<' => out.push_str("&lt;"),
+            '>' => out.push_str("&gt;"),
+            '"' => out.push_str("&quot;"),
+            '\'' => out.push_str("&apos;"),
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Schemes of the links that may be opened.
+const SAFE_SCHEMES: [&str; 3] = ["https://", "http://", "mailto:"];
+
+/// Returns `true` if the link may be passed to the link opener.
+///
+/// Links come from untrusted senders, so only `http`, `https` and `mailto` links
+/// are accepted. Local files, e.g. `.desktop` files, and anything the opener could
+/// take for an option are rejected.
+pub fn is_safe_link(link: &str) -> bool {
+    let has_safe_scheme = SAFE_SCHEMES.iter().any(|scheme| {
+        link.get(..scheme.len())
+            .map_or(false, |prefix| prefix.eq_ignore_ascii_case(scheme))
+            && link.len() > scheme.len()
+    });
+    has_safe_scheme && !link.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
+/// Finds bare URLs in text, dropping trailing punctuation.
+fn find_urls(text: &str) -> Vec<&str> {
+    text.split(|c: char| c.is_whitespace() || c == '<' || c == '>')
+        .filter_map(|word| {
+            let start = SAFE_SCHEMES
+                .iter()
+                .filter_map(|scheme| word.find(scheme))
+                .min()?;
+            let url = word[start..].trim_end_matches(|c: char| ".,;:!?)]}'\"".contains(c));
+            Some(url).filter(|url| !url.ends_with("//") && !url.ends_with(':'))
+        })
+        .collect()
+}
+
+/// Checks that the content between `<` and `>` looks like a tag, e.g. not `<3`.
+fn is_element(content: &str) -> bool {
+    content
+        .strip_prefix('/')
+        .unwrap_or(content)
+        .starts_with(|c: char| c.is_ascii_alphabetic())
+}
+
+/// Turns a tag into a node, keeping track of the open tags.
+///
+/// Returns `None` for unsupported tags and unbalanced end tags.
+fn parse_element(content: &str, open: &mut Vec<Tag>) -> Option<Node> {
+    if let Some(name) = content.strip_prefix('/') {
+        let name = name.trim().to_ascii_lowercase();
+        return match open.last() {
+            Some(tag) if tag.name() == name => open.pop().map(Node::End),
+            _ => None,
+        };
+    }
+
+    let content = content.strip_suffix('/').unwrap_or(content);
+    let (name, attributes) = content
+        .split_once(char::is_whitespace)
+        .unwrap_or((content, ""));
+    let tag = match name.to_ascii_lowercase().as_str() {
+        "b" => Tag::Bold,
+        "i" => Tag::Italic,
+        "u" => Tag::Underline,
+        "a" => Tag::Link(attribute(attributes, "href").unwrap_or_default()),
+        "img" => {
+            return Some(Node::Image {
+                src: attribute(attributes, "src").unwrap_or_default(),
+                alt: attribute(attributes, "alt").unwrap_or_default(),
+            })
+        }
+        _ => return None,
+    };
+    open.push(tag.clone());
+    Some(Node::Start(tag))
+}
+
+/// Reads the value of a quoted attribute.
+fn attribute(attributes: &str, name: &str) -> Option<String> {
+    let mut rest = attributes;
+    loop {
+        rest = rest.trim_start();
+        let (key, after) = rest.split_once('=')?;
+        let after = after.trim_start();
+        let quote = after.chars().next().filter(|c| *c == '"' || *c == '\'')?;
+        let (value, remainder) = after[1..].split_once(quote)?;
+        if key.trim().eq_ignore_ascii_case(name) {
+            return Some(decode_entities(value));
+        }
+        rest = remainder;
+    }
+}
+
+/// Replaces character and predefined entity references, keeping unknown ones as text.
+fn decode_entities(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(start) = rest.find('&') {
+        out.push_str(&rest[..start]);
+        let after = &rest[start + 1..];
+        let decoded = after.find(';').and_then(|end| {
+            let entity = &after[..end];
+            let c = match entity {
+                "amp" => Some('&'),
+                "lt" => Some('<'),
+                "gt" => Some('>'),
+                "quot" => Some('"'),
+                "apos" => Some('\''),
+                _ => entity
+                    .strip_prefix("#x")
+                    .or_else(|| entity.strip_prefix("#X"))
+                    .map(|hex| u32::from_str_radix(hex, 16))
+                    .or_else(|| entity.strip_prefix('#').map(|dec| dec.parse::<u32>()))
+                    .and_then(|code| code.ok())
+                    .and_then(char::from_u32),
+            };
+            c.map(|c| (c, &after[end + 1..]))
+        });
+
+        match decoded {
+            Some((c, remainder)) => {
+                out.push(c);
+                rest = remainder;
+            }
+            None => {
+                out.push('&');
+                rest = after;
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markup_supported_tags() {
+        let unit = Markup::parse(
+            r#"<b>bold</b> <I>italic</I> <u>under</u> <a href="https://example.com/?a=1&amp;b=2">link</a> <img src="x.png" alt="pic"/>"#,
+        );
+
+        assert_eq!(unit.to_plain(), "bold italic under link pic");
+        assert_eq!(
+            unit.to_pango(),
+            "<b>bold</b> <i>italic</i> <u>under</u> <u>link</u> pic"
+        );
+        assert!(unit
+            .nodes()
+            .contains(&Node::Start(Tag::Link("https://example.com/?a=1&b=2".to_string()))));
+    }
+
+    #[test]
+    fn markup_unsupported_and_malformed() {
+        let unit = Markup::parse("<p>Hi <span foreground='red'>there</span></p> <3 & a<b");
+
+        assert_eq!(unit.to_plain(), "Hi there <3 & a<b");
+        assert_eq!(unit.to_pango(), "Hi there &lt;3 &amp; a&lt;b");
+    }
+
+    #[test]
+    fn markup_unbalanced_tags() {
+        let unit = Markup::parse("<b><i>text</b> end");
+
+        assert_eq!(unit.to_pango(), "<b><i>text end</i></b>", "tags are always balanced");
+    }
+
+    #[test]
+    fn markup_links() {
+        let unit = Markup::parse(
+            r#"See <a href="https://example.com/a">this</a>, (https://example.com/b). Also https://example.com/a and "mailto:me@example.com"! http:// is not a link"#,
+        );
+
+        assert_eq!(
+            unit.links(),
+            vec![
+                "https://example.com/a",
+                "https://example.com/b",
+                "mailto:me@example.com"
+            ]
+        );
+    }
+
+    #[test]
+    fn markup_unsafe_links() {
+        let unit = Markup::parse(
+            r#"<a href="file:///home/me/evil.desktop">a</a> <a href="-oProxyCommand=x">b</a> <a href="javascript:alert(1)">c</a> <a href="HTTPS://example.com/ok">d</a> <a href="https://example.com/x y">e</a>"#,
+        );
+
+        assert_eq!(unit.links(), vec!["HTTPS://example.com/ok"]);
+        assert!(is_safe_link("mailto:me@example.com"));
+        assert!(!is_safe_link("https://"));
+        assert!(!is_safe_link("/etc/passwd"));
+        assert!(!is_safe_link("smb://server/share"));
+    }
+
+    #[test]
+    fn markup_entities() {
+        let unit = Markup::parse("&lt;tag&gt; &#65;&#x42; &unknown; &amp;amp;");
+
+        assert_eq!(unit.to_plain(), "<tag> AB &unknown; &amp;");
+        assert_eq!(unit.to_pango(), "&lt;tag&gt; AB &amp;unknown; &amp;amp;");
+    }
+}
@@ -1,19 +1,33 @@
-use serde::Serialize;
-use serde_repr::Serialize_repr;
-use std::collections::HashMap;
+use crate::hint::Hint;
+use crate::image::ImageData;
+use crate::markup::Markup;
+use serde::{Deserialize, Serialize};
+use serde_repr::{Deserialize_repr, Serialize_repr};
+use std::collections::{HashMap, VecDeque};
 use std::fmt::Display;
-use std::sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
 
 /// Name of the template for rendering the notification message.
 pub const NOTIFICATION_MESSAGE_TEMPLATE: &str = "notification_message_template";
 
+/// Default number of dismissed notifications kept in the history.
+pub const DEFAULT_HISTORY_SIZE: usize = 20;
+
+/// Number of events a subscriber may lag behind before it is dropped.
+pub const SUBSCRIBER_CAPACITY: usize = 256;
+
 /// Possible urgency levels for the notification.
-#[derive(Clone, Debug, Serialize_repr, Copy, PartialEq)]
+#[derive(Clone, Debug, Default, Serialize_repr, Deserialize_repr, Copy, PartialEq)]
 #[repr(u8)]
 pub enum Urgency {
     /// Urgency - low
     Low,
     /// Urgency - normal
+    #[default]
     Normal,
     /// Urgency - high
     Critical,
@@ -25,6 +39,19 @@ impl Display for Urgency {
     }
 }
 
+impl FromStr for Urgency {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "critical" => Ok(Self::Critical),
+            _ => Err(format!("unknown urgency '{s}', expected low, normal or critical")),
+        }
+    }
+}
+
 impl From<u64> for Urgency {
     fn from(value: u64) -> Self {
         match value {
@@ -36,15 +63,11 @@ impl From<u64> for Urgency {
     }
 }
 
-impl Default for Urgency {
-    fn default() -> Self {
-        Self::Normal
-    }
-}
 /// Representation of a notification.
 ///
 /// See [D-Bus Notify Parameters](https://specifications.freedesktop.org/notification-spec/latest/ar01s09.html)
-#[derive(Clone, Debug, Default, Serialize)]
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
 pub struct Notification {
     /// notification id
     pub id: u32,
@@ -52,46 +75,206 @@ pub struct Notification {
     pub summary: String,
     /// body
     pub body: String,
+    /// body as plain text, without markup
+    pub body_plain: String,
+    /// body as Pango markup, safe to display in rofi
+    pub body_pango: String,
+    /// links found in the body
+    pub links: Vec<String>,
     /// name of app that generated the notification
     pub application: String,
+    /// human-friendly name of the app, from its desktop entry if known
+    pub app_name: String,
+    /// desktop file id of the app, from the `desktop-entry` hint
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub desktop_entry: Option<String>,
     /// icon name from app that generated the notification
     pub icon: String,
+    /// path to the icon file, resolved from the icon name
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub icon_path: Option<PathBuf>,
+    /// path to the image sent with the notification
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub image: Option<PathBuf>,
+    /// raw image sent with the notification, until it is cached
+    #[serde(skip)]
+    pub image_data: Option<ImageData>,
     /// urgency of notification
     pub urgency: Urgency,
     /// possible actions against notification
     pub actions: Vec<String>,
     /// other notification metadata
-    pub hints: HashMap<String, String>,
+    pub hints: HashMap<String, Hint>,
+    /// whether the notification is kept after an action is invoked
+    pub resident: bool,
+    /// whether the user has seen the notification
+    pub seen: bool,
+    /// whether the notification is neither saved nor kept in the history, from the `transient` hint
+    pub transient: bool,
+    /// whether the notification is left out of the history once removed
+    pub skip_history: bool,
+    /// expiration timeout requested by the sender in milliseconds, -1 for the server default
+    pub expire_timeout: i32,
     /// time that notification was received by daemon
     pub timestamp: u64,
+    /// time that notification was last replaced by the sender
+    pub updated_at: u64,
+}
+
+impl Notification {
+    /// Returns `true` if the notification provides an action with the given key.
+    ///
+    /// Actions are stored as a flat list of key and label pairs.
+    pub fn has_action(&self, key: &str) -> bool {
+        self.actions.iter().step_by(2).any(|k| k == key)
+    }
+
+    /// Changes the urgency, keeping the `urgency` hint in line.
+    pub fn set_urgency(&mut self, urgency: Urgency) {
+        self.urgency = urgency;
+        self.hints.insert("urgency".to_string(), Hint::Byte(urgency as u8));
+    }
+
+    /// Renders the plain text and Pango versions of the body and extracts its links.
+    pub fn render_body(&mut self) {
+        let markup = Markup::parse(&self.body);
+        self.body_plain = markup.to_plain();
+        self.body_pango = markup.to_pango();
+        self.links = markup.links();
+    }
+
+    /// Returns `true` if the notification was sent by the given app.
+    ///
+    /// Matches the desktop entry, the raw and the human-friendly app names.
+    pub fn is_from_app(&self, app: &str) -> bool {
+        !app.is_empty()
+            && (self.desktop_entry.as_deref() == Some(app)
+                || self.application == app
+                || self.app_name == app)
+    }
+
+    /// Returns `true` if both notifications were sent by the same app.
+    pub fn is_similar(&self, other: &Notification) -> bool {
+        match (&self.desktop_entry, &other.desktop_entry) {
+            (Some(entry), Some(other_entry)) => entry == other_entry,
+            _ => {
+                other.is_from_app(&self.application)
+                    || (!self.app_name.is_empty() && other.app_name == self.app_name)
+            }
+        }
+    }
+}
+
+/// Reasons for closing a notification.
+///
+/// See [NotificationClosed](https://specifications.freedesktop.org/notification-spec/latest/ar01s09.html)
+#[derive(Clone, Debug, Copy, PartialEq)]
+#[repr(u32)]
+pub enum CloseReason {
+    /// The notification expired
+    Expired = 1,
+    /// The notification was dismissed by the user
+    Dismissed = 2,
+    /// The notification was closed by a call to CloseNotification
+    Closed = 3,
+    /// Undefined/reserved reasons
+    Undefined = 4,
 }
 
 /// Specifies internal events
 #[derive(Debug)]
 pub enum Action {
-    /// Show a notification event from dbus
-    Show(Notification),
-    /// Show the last notification from dbus
-    ShowLast,
+    /// Show a notification event from dbus, replying with its id
+    ///
+    /// The notification replaces the one with its id, if any, and gets a new id otherwise.
+    Show(Box<Notification>, Sender<u32>),
+    /// Restore the last dismissed notification, replying with its id
+    ShowLast(Sender<Option<u32>>),
     /// Close a notification event from dbus
-    Close(Option<u32>),
+    Close(u32),
+    /// Close the newest notification, replying with its id
+    CloseLast(Sender<Option<u32>>),
     /// Close all the notifications event from dbus
     CloseAll,
+    /// Notifications were removed from the store by a client
+    Closed(Vec<u32>, CloseReason),
+    /// Invoke the action with the given key on a notification
+    Invoke(u32, String),
+    /// Open the link with the given index of a notification
+    OpenLink(u32, usize),
+    /// A notification was marked as seen by a client
+    Seen(u32),
+    /// Pause or resume delivery, toggling if unspecified, replying with the paused state
+    Pause(Option<bool>, Sender<bool>),
     /// A fatal problem occurred, exit
     Shutdown(crate::error::Error),
 }
 
+impl Action {
+    /// Sends an action to the main loop and waits for its reply.
+    ///
+    /// Returns `None` if the main loop is gone.
+    pub fn request<T>(sender: &Sender<Action>, action: impl FnOnce(Sender<T>) -> Action) -> Option<T> {
+        let (reply_sender, reply) = mpsc::channel();
+        sender.send(action(reply_sender)).ok()?;
+        reply.recv().ok()
+    }
+}
+
+/// Change of the delivered notifications, published to the subscribers of the store.
+#[derive(Clone, Debug)]
+pub enum StoreEvent {
+    /// A notification was added
+    Added(Notification),
+    /// A notification was replaced by its sender
+    Replaced(Notification),
+    /// A notification was removed
+    Removed(Notification),
+    /// The state of a notification changed, e.g. it was seen
+    Changed(Notification),
+}
+
+impl StoreEvent {
+    /// Returns the notification the event is about.
+    pub fn notification(&self) -> &Notification {
+        match self {
+            StoreEvent::Added(notification)
+            | StoreEvent::Replaced(notification)
+            | StoreEvent::Removed(notification)
+            | StoreEvent::Changed(notification) => notification,
+        }
+    }
+}
+
 /// Notification database
 #[derive(Debug)]
 pub struct NotificationStore {
     /// Inner type that holds the notifications in thread-safe way.
     inner: Arc<RwLock<Vec<Notification>>>,
+    /// Removed notifications, the most recently removed last.
+    history: Arc<RwLock<VecDeque<Notification>>>,
+    /// Maximum number of notifications kept in the history.
+    history_size: usize,
+    /// Notifications held back while delivery is paused, in order of arrival.
+    queue: Arc<RwLock<Vec<Notification>>>,
+    /// Whether delivery is paused.
+    paused: Arc<AtomicBool>,
+    /// Incremented on every change of the notifications, the history or the paused state.
+    revision: Arc<AtomicU64>,
+    /// Receivers of the changes of the delivered notifications.
+    subscribers: Arc<Mutex<Vec<SyncSender<StoreEvent>>>>,
 }
 
 impl Clone for NotificationStore {
     fn clone(&self) -> Self {
         Self {
             inner: Arc::clone(&self.inner),
+            history: Arc::clone(&self.history),
+            history_size: self.history_size,
+            queue: Arc::clone(&self.queue),
+            paused: Arc::clone(&self.paused),
+            revision: Arc::clone(&self.revision),
+            subscribers: Arc::clone(&self.subscribers),
         }
     }
 }
@@ -99,8 +282,19 @@ impl Clone for NotificationStore {
 impl NotificationStore {
     /// Initializes the notification db
     pub fn init() -> Self {
+        Self::init_with_history(DEFAULT_HISTORY_SIZE)
+    }
+
+    /// Initializes the notification db, keeping up to `history_size` removed notifications
+    pub fn init_with_history(history_size: usize) -> Self {
         Self {
             inner: Arc::new(RwLock::new(Vec::new())),
+            history: Arc::new(RwLock::new(VecDeque::new())),
+            history_size,
+            queue: Arc::new(RwLock::new(Vec::new())),
+            paused: Arc::new(AtomicBool::new(false)),
+            revision: Arc::new(AtomicU64::new(0)),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
         }
     }
 
@@ -112,9 +306,156 @@ impl NotificationStore {
             .len()
     }
 
+    /// Returns the number of notifications the user has not seen yet.
+    pub fn unseen_count(&self) -> usize {
+        self.ds_read().iter().filter(|n| !n.seen).count()
+    }
+
+    /// Returns the number of critical notifications.
+    pub fn critical_count(&self) -> usize {
+        self.ds_read()
+            .iter()
+            .filter(|n| n.urgency == Urgency::Critical)
+            .count()
+    }
+
+    /// Returns a number that changes whenever the notifications, the history or the paused state change.
+    pub fn revision(&self) -> u64 {
+        self.revision.load(Ordering::SeqCst)
+    }
+
+    /// Subscribes to the changes of the delivered notifications.
+    ///
+    /// Returns the notifications at the time of the call, the events describe the
+    /// changes made afterwards. Notifications loaded with [`NotificationStore::load`]
+    /// or held back while paused are not published. Dropping the receiver ends the
+    /// subscription, and so does lagging more than [`SUBSCRIBER_CAPACITY`] events behind,
+    /// which disconnects the receiver once it has caught up.
+    pub fn subscribe(&self) -> (Vec<Notification>, Receiver<StoreEvent>) {
+        let ds = self.ds_read();
+        let (sender, receiver) = mpsc::sync_channel(SUBSCRIBER_CAPACITY);
+        self.subscribers
+            .lock()
+            .expect("can lock subscribers")
+            .push(sender);
+        (ds.clone(), receiver)
+    }
+
     /// Adds a new notifications to manage.
     pub fn add(&self, notification: Notification) {
-        self.ds_write().push(notification);
+        let mut ds = self.ds_write();
+        self.publish(StoreEvent::Added(notification.clone()));
+        ds.push(notification);
+    }
+
+    /// Replaces the notification with the same id, or adds it if there is none.
+    ///
+    /// A replaced notification keeps its original `timestamp` and records the time
+    /// of the replacement in `updated_at`. It stays at its position unless
+    /// `move_to_end` is set, in which case it is listed as the newest notification.
+    ///
+    /// Returns `true` if an existing notification was replaced.
+    pub fn upsert(&self, mut notification: Notification, move_to_end: bool) -> bool {
+        let mut ds = self.ds_write();
+
+        match ds.iter().position(|n| n.id == notification.id) {
+            Some(index) => {
+                notification.updated_at = notification.timestamp;
+                notification.timestamp = ds[index].timestamp;
+                self.publish(StoreEvent::Replaced(notification.clone()));
+                if move_to_end {
+                    ds.remove(index);
+                    ds.push(notification);
+                } else {
+                    ds[index] = notification;
+                }
+                true
+            }
+            None => {
+                self.publish(StoreEvent::Added(notification.clone()));
+                ds.push(notification);
+                false
+            }
+        }
+    }
+
+    /// Returns `true` if a notification with the given id is managed, including held back ones.
+    pub fn contains(&self, id: u32) -> bool {
+        self.ds_read().iter().any(|n| n.id == id) || self.queue_write().iter().any(|n| n.id == id)
+    }
+
+    /// Returns `true` if a notification with the given id is delivered.
+    pub fn is_delivered(&self, id: u32) -> bool {
+        self.ds_read().iter().any(|n| n.id == id)
+    }
+
+    /// Returns `true` if delivery is paused.
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::SeqCst)
+    }
+
+    /// Holds back a notification until delivery is resumed.
+    ///
+    /// A held back notification with the same id is replaced.
+    pub fn enqueue(&self, notification: Notification) {
+        self.revision.fetch_add(1, Ordering::SeqCst);
+        let mut queue = self.queue_write();
+        match queue.iter().position(|n| n.id == notification.id) {
+            Some(index) => queue[index] = notification,
+            None => queue.push(notification),
+        }
+    }
+
+    /// Returns the number of held back notifications.
+    pub fn queued_count(&self) -> usize {
+        self.queue_write().len()
+    }
+
+    /// Return a copy of the held back notifications, in order of arrival
+    pub fn queued(&self) -> Vec<Notification> {
+        self.queue_write().clone()
+    }
+
+    /// Replaces the content of the store, e.g. with the state of a previous run.
+    pub fn load(
+        &self,
+        notifications: Vec<Notification>,
+        history: Vec<Notification>,
+        queue: Vec<Notification>,
+        paused: bool,
+    ) {
+        *self.ds_write() = notifications;
+        let mut stored_history = self.history_write();
+        *stored_history = history.into();
+        while stored_history.len() > self.history_size {
+            stored_history.pop_front();
+        }
+        drop(stored_history);
+        *self.queue_write() = queue;
+        self.paused.store(paused, Ordering::SeqCst);
+    }
+
+    /// Pauses or resumes delivery.
+    ///
+    /// Resuming delivers the held back notifications in order of arrival.
+    /// Returns the ids of the delivered notifications, with `true` for the ones
+    /// that replaced a delivered notification.
+    pub fn set_paused(&self, paused: bool) -> Vec<(u32, bool)> {
+        if self.paused.swap(paused, Ordering::SeqCst) != paused {
+            self.revision.fetch_add(1, Ordering::SeqCst);
+        }
+        if paused {
+            return Vec::new();
+        }
+
+        let queued: Vec<Notification> = self.queue_write().drain(..).collect();
+        queued
+            .into_iter()
+            .map(|notification| {
+                let id = notification.id;
+                (id, self.upsert(notification, true))
+            })
+            .collect()
     }
 
     /// Return a copy of all active notifications at time of call
@@ -122,22 +463,117 @@ impl NotificationStore {
         self.ds_read().iter().cloned().collect()
     }
 
-    /// Marks the given notification as read.
-    pub fn delete(&self, id: u32) {
+    /// Return a copy of the notification with the given id
+    pub fn get(&self, id: u32) -> Option<Notification> {
+        self.ds_read().iter().find(|n| n.id == id).cloned()
+    }
+
+    /// Marks the given notification as read, dropping it if it is held back.
+    ///
+    /// Returns `true` if the notification was present.
+    pub fn delete(&self, id: u32) -> bool {
+        let mut queue = self.queue_write();
+        let queued = queue.len();
+        queue.retain(|e| e.id != id);
+        let dropped = queue.len() != queued;
+        drop(queue);
+
+        !self.delete_where(|e| e.id == id).is_empty() || dropped
+    }
+
+    /// Marks all the notifications as read.
+    ///
+    /// Returns the ids of the removed notifications.
+    pub fn delete_all(&self) -> Vec<u32> {
+        self.delete_where(|_| true)
+    }
+
+    /// Marks the newest notification as read.
+    ///
+    /// Returns the id of the removed notification.
+    pub fn delete_newest(&self) -> Option<u32> {
+        let id = self.ds_read().last()?.id;
+        self.delete(id).then_some(id)
+    }
+
+    /// Moves the most recently removed notification from the history back to the store.
+    ///
+    /// Returns the id of the restored notification.
+    pub fn restore(&self) -> Option<u32> {
         let mut ds = self.ds_write();
+        let notification = self.history_write().pop_back()?;
+        let id = notification.id;
 
         ds.retain(|e| e.id != id);
+        self.publish(StoreEvent::Added(notification.clone()));
+        ds.push(notification);
+        Some(id)
     }
-    /// Marks all the notifications as read.
-    pub fn delete_all(&self) {
-        self.ds_write().clear()
+
+    /// Return a copy of the removed notifications, the most recently removed last
+    pub fn history(&self) -> Vec<Notification> {
+        self.history
+            .read()
+            .expect("can read from history")
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Marks the notifications of the given app as read.
+    ///
+    /// Returns the ids of the removed notifications.
+    pub fn delete_from_app(&self, app_name: String) -> Vec<u32> {
+        self.delete_where(|e| e.is_from_app(&app_name))
+    }
+
+    /// Marks the notifications of the same app as the given notification as read.
+    ///
+    /// Returns the ids of the removed notifications.
+    pub fn delete_similar(&self, id: u32) -> Vec<u32> {
+        match self.get(id) {
+            Some(source) => self.delete_where(|e| e.id == id || source.is_similar(e)),
+            None => Vec::new(),
+        }
     }
 
-    /// Marks the given notification as read.
-    pub fn delete_from_app(&self, app_name: String) {
+    /// Moves the matching notifications to the history.
+    fn delete_where<F: Fn(&Notification) -> bool>(&self, predicate: F) -> Vec<u32> {
         let mut ds = self.ds_write();
+        let (removed, kept): (Vec<Notification>, Vec<Notification>) =
+            ds.drain(..).partition(|e| predicate(e));
+        *ds = kept;
 
-        ds.retain(|e| e.application != app_name);
+        let ids = removed.iter().map(|n| n.id).collect();
+        let mut history = self.history_write();
+        for notification in removed {
+            self.publish(StoreEvent::Removed(notification.clone()));
+            history.retain(|e| e.id != notification.id);
+            if !notification.transient && !notification.skip_history {
+                history.push_back(notification);
+            }
+        }
+        while history.len() > self.history_size {
+            history.pop_front();
+        }
+        ids
+    }
+
+    /// Marks the given notification as seen, reducing a critical urgency to normal.
+    ///
+    /// Returns `true` if the notification was present.
+    pub fn mark_seen(&self, id: u32) -> bool {
+        match self.ds_write().iter_mut().find(|n| n.id == id) {
+            Some(notification) => {
+                notification.seen = true;
+                if notification.urgency == Urgency::Critical {
+                    notification.set_urgency(Urgency::Normal);
+                }
+                self.publish(StoreEvent::Changed(notification.clone()));
+                true
+            }
+            None => false,
+        }
     }
 
     /// set the urgency of the notification
@@ -148,19 +584,46 @@ impl NotificationStore {
             .iter_mut()
             .find(|n| n.id == id);
 
-        if notification.is_some() {
-            let notification = notification.unwrap();
-            notification.urgency = target_urgency;
+        if let Some(notification) = notification {
+            notification.set_urgency(target_urgency);
+            self.publish(StoreEvent::Changed(notification.clone()));
         }
     }
 
+    /// Sends an event to the subscribers, dropping the ones that are gone or lagging behind.
+    ///
+    /// Called with the store locked, so events are received in order of the changes
+    /// and a stalled subscriber must not block it.
+    fn publish(&self, event: StoreEvent) {
+        self.subscribers
+            .lock()
+            .expect("can lock subscribers")
+            .retain(|subscriber| match subscriber.try_send(event.clone()) {
+                Ok(()) => true,
+                Err(TrySendError::Full(_)) => {
+                    log::warn!("Dropping a subscriber lagging {} events behind", SUBSCRIBER_CAPACITY);
+                    false
+                }
+                Err(TrySendError::Disconnected(_)) => false,
+            });
+    }
+
     fn ds_read(&self) -> RwLockReadGuard<'_, Vec<Notification>> {
         self.inner.read().expect("can read from db store")
     }
 
     fn ds_write(&self) -> RwLockWriteGuard<'_, Vec<Notification>> {
+        self.revision.fetch_add(1, Ordering::SeqCst);
         self.inner.write().expect("can write to db store")
     }
+
+    fn history_write(&self) -> RwLockWriteGuard<'_, VecDeque<Notification>> {
+        self.history.write().expect("can write to history")
+    }
+
+    fn queue_write(&self) -> RwLockWriteGuard<'_, Vec<Notification>> {
+        self.queue.write().expect("can write to queue")
+    }
 }
 
 #[cfg(test)]
@@ -191,8 +654,7 @@ mod tests {
 
         let binding = unit.items();
         let retrieved_item = binding
-            .iter()
-            .next()
+            .first()
             .expect("Can get added notification from store");
 
         assert_eq!(added_item.id, retrieved_item.id);
@@ -202,14 +664,14 @@ mod tests {
     fn notification_store_delete_one() {
         let (unit, _) = add_single_item();
 
-        unit.delete(0); // invalid id
+        assert!(!unit.delete(0), "invalid id is not reported as deleted");
         assert_eq!(
             unit.count(),
             1,
             "no change after attempt to delete invalid id"
         );
 
-        unit.delete(1);
+        assert!(unit.delete(1), "valid id is reported as deleted");
         assert_eq!(unit.count(), 0, "count down by own after deleting valid id");
     }
 
@@ -217,25 +679,161 @@ mod tests {
     fn notification_store_delete_by_app() {
         let (unit, _) = add_single_item();
 
-        unit.delete_from_app("invalid_app_name".to_string()); // invalid app name
+        let removed = unit.delete_from_app("invalid_app_name".to_string()); // invalid app name
+        assert!(removed.is_empty());
         assert_eq!(
             unit.count(),
             1,
             "no change after attempt to delete invalid id"
         );
 
-        unit.delete_from_app("test-app".to_string());
+        let removed = unit.delete_from_app("test-app".to_string());
+        assert_eq!(removed, vec![1]);
         assert_eq!(unit.count(), 0, "count down by own after deleting valid id");
     }
 
+    #[test]
+    fn notification_store_delete_similar() {
+        let (unit, _) = add_single_item();
+        unit.add(Notification {
+            id: 2,
+            application: "Test App".to_string(),
+            app_name: "Test".to_string(),
+            desktop_entry: Some("org.test.App".to_string()),
+            ..Default::default()
+        });
+        unit.add(Notification {
+            id: 3,
+            application: "test".to_string(),
+            app_name: "Test".to_string(),
+            desktop_entry: Some("org.test.App".to_string()),
+            ..Default::default()
+        });
+
+        assert!(unit.delete_similar(0).is_empty(), "invalid id");
+        assert_eq!(unit.delete_similar(2), vec![2, 3], "grouped by desktop entry");
+        assert_eq!(unit.count(), 1);
+    }
+
+    #[test]
+    fn notification_render_body() {
+        let mut n = Notification {
+            body: "<b>Tom &amp; Jerry</b> <blink>&lt;3</blink>".to_string(),
+            ..Default::default()
+        };
+
+        n.render_body();
+
+        assert_eq!(n.body_plain, "Tom & Jerry <3");
+        assert_eq!(n.body_pango, "<b>Tom &amp; Jerry</b> &lt;3");
+        assert!(n.links.is_empty());
+    }
+
+    #[test]
+    fn notification_is_from_app() {
+        let n = Notification {
+            application: "raw-name".to_string(),
+            app_name: "Friendly Name".to_string(),
+            desktop_entry: Some("org.test.App".to_string()),
+            ..Default::default()
+        };
+
+        assert!(n.is_from_app("raw-name"));
+        assert!(n.is_from_app("Friendly Name"));
+        assert!(n.is_from_app("org.test.App"));
+        assert!(!n.is_from_app(""));
+        assert!(!n.is_from_app("other"));
+    }
+
     #[test]
     fn notification_store_delete_all() {
         let (unit, _) = add_single_item();
 
-        unit.delete_all();
+        assert_eq!(unit.delete_all(), vec![1]);
         assert_eq!(unit.count(), 0, "count down by own after deleting valid id");
     }
 
+    #[test]
+    fn notification_store_history() {
+        let unit = NotificationStore::init_with_history(2);
+        for id in 1..=3 {
+            unit.add(Notification {
+                id,
+                ..Default::default()
+            });
+        }
+
+        assert_eq!(unit.restore(), None, "history is empty");
+        assert_eq!(unit.delete_newest(), Some(3));
+        assert_eq!(unit.delete_all(), vec![1, 2]);
+
+        let ids: Vec<u32> = unit.history().iter().map(|n| n.id).collect();
+        assert_eq!(ids, vec![1, 2], "history is bounded, oldest removed first");
+
+        assert_eq!(unit.restore(), Some(2), "most recently removed is restored");
+        assert_eq!(unit.count(), 1);
+        assert_eq!(unit.delete_newest(), Some(2));
+        assert_eq!(unit.delete_newest(), None, "store is empty");
+
+        unit.add(Notification {
+            id: 4,
+            skip_history: true,
+            ..Default::default()
+        });
+        unit.add(Notification {
+            id: 5,
+            transient: true,
+            ..Default::default()
+        });
+        assert_eq!(unit.delete_all(), vec![4, 5]);
+        let ids: Vec<u32> = unit.history().iter().map(|n| n.id).collect();
+        assert_eq!(ids, vec![1, 2], "skipped notifications are not archived");
+    }
+
+    #[test]
+    fn notification_store_pause() {
+        let unit = NotificationStore::init();
+        unit.add(Notification {
+            id: 3,
+            ..Default::default()
+        });
+        unit.set_paused(true);
+        assert!(unit.is_paused());
+
+        unit.enqueue(Notification {
+            id: 1,
+            ..Default::default()
+        });
+        unit.enqueue(Notification {
+            id: 2,
+            ..Default::default()
+        });
+        unit.enqueue(Notification {
+            id: 1,
+            summary: "replaced".to_string(),
+            ..Default::default()
+        });
+
+        unit.enqueue(Notification {
+            id: 3,
+            summary: "replaced".to_string(),
+            ..Default::default()
+        });
+
+        assert_eq!(unit.count(), 1, "held back notifications are not counted");
+        assert_eq!(unit.queued_count(), 3);
+        assert!(unit.contains(2));
+        assert!(!unit.is_delivered(2));
+
+        assert!(unit.delete(2), "held back notification can be closed");
+        assert!(unit.history().is_empty(), "dropped notification is not archived");
+
+        assert_eq!(unit.set_paused(false), vec![(1, false), (3, true)]);
+        assert!(!unit.is_paused());
+        assert_eq!(unit.queued_count(), 0);
+        assert_eq!(unit.get(1).map(|n| n.summary), Some("replaced".to_string()));
+    }
+
     #[test]
     fn notification_store_change_urgency() {
         let (unit, _) = add_single_item();
@@ -243,12 +841,185 @@ mod tests {
         unit.set_urgency(1, Urgency::Low);
 
         let notifications = unit.items();
-        let n = notifications.iter().next().expect("Has added element");
+        let n = notifications.first().expect("Has added element");
 
         assert_eq!(n.id, 1);
         assert_eq!(n.urgency, Urgency::Low);
     }
 
+    #[test]
+    fn notification_store_mark_seen() {
+        let unit = NotificationStore::init();
+        unit.add(Notification {
+            id: 1,
+            urgency: Urgency::Critical,
+            ..Default::default()
+        });
+        unit.add(Notification {
+            id: 2,
+            urgency: Urgency::Low,
+            ..Default::default()
+        });
+        assert_eq!(unit.unseen_count(), 2);
+        assert_eq!(unit.critical_count(), 1);
+
+        let revision = unit.revision();
+        assert!(unit.mark_seen(1));
+        assert!(!unit.mark_seen(3));
+
+        assert!(unit.revision() > revision, "mutation changes the revision");
+        assert_eq!(unit.unseen_count(), 1);
+        assert_eq!(unit.critical_count(), 0);
+        assert_eq!(
+            unit.get(1).and_then(|n| n.hints.get("urgency").cloned()),
+            Some(Hint::Byte(1)),
+            "hint follows urgency"
+        );
+
+        assert!(unit.mark_seen(2));
+        assert_eq!(unit.get(2).map(|n| n.urgency), Some(Urgency::Low), "only critical is reduced");
+
+        let revision = unit.revision();
+        unit.items();
+        unit.set_paused(false);
+        assert_eq!(unit.revision(), revision, "reads do not change the revision");
+    }
+
+    #[test]
+    fn notification_store_subscribe() {
+        let unit = NotificationStore::init();
+        unit.add(Notification::default());
+        let (notifications, events) = unit.subscribe();
+        assert_eq!(notifications.len(), 1);
+        let notification = |id, urgency| Notification {
+            id,
+            urgency,
+            ..Default::default()
+        };
+
+        unit.add(notification(1, Urgency::Normal));
+        unit.upsert(notification(1, Urgency::Critical), false);
+        unit.upsert(notification(2, Urgency::Low), false);
+        unit.mark_seen(1);
+        unit.delete(2);
+        unit.set_paused(true);
+        unit.enqueue(notification(3, Urgency::Normal));
+
+        let received: Vec<(&str, u32, Urgency)> = events
+            .try_iter()
+            .map(|event| {
+                let kind = match event {
+                    StoreEvent::Added(_) => "added",
+                    StoreEvent::Replaced(_) => "replaced",
+                    StoreEvent::Removed(_) => "removed",
+                    StoreEvent::Changed(_) => "changed",
+                };
+                (kind, event.notification().id, event.notification().urgency)
+            })
+            .collect();
+        assert_eq!(
+            received,
+            vec![
+                ("added", 1, Urgency::Normal),
+                ("replaced", 1, Urgency::Critical),
+                ("added", 2, Urgency::Low),
+                ("changed", 1, Urgency::Normal),
+                ("removed", 2, Urgency::Low),
+            ],
+            "held back notifications are not published"
+        );
+
+        drop(events);
+        unit.add(notification(4, Urgency::Normal));
+        assert!(unit.subscribers.lock().expect("can lock subscribers").is_empty());
+    }
+
+    #[test]
+    fn notification_store_drops_lagging_subscribers() {
+        let unit = NotificationStore::init();
+        let (_, events) = unit.subscribe();
+        for id in 0..=SUBSCRIBER_CAPACITY as u32 {
+            unit.add(Notification {
+                id,
+                ..Default::default()
+            });
+        }
+
+        assert!(unit.subscribers.lock().expect("can lock subscribers").is_empty());
+        assert_eq!(events.try_iter().count(), SUBSCRIBER_CAPACITY, "keeps the events sent in time");
+        assert!(events.recv().is_err(), "disconnected once caught up");
+    }
+
+    #[test]
+    fn notification_has_action() {
+        let (unit, _) = add_single_item();
+
+        let n = unit.get(1).expect("Has added element");
+
+        assert!(n.has_action("default"));
+        assert!(!n.has_action("Open"), "labels are not action keys");
+        assert!(!n.has_action("invalid"));
+        assert!(unit.get(0).is_none());
+    }
+
+    #[test]
+    fn notification_store_upsert_in_place() {
+        let (unit, added_item) = add_single_item();
+        unit.add(Notification {
+            id: 2,
+            ..Default::default()
+        });
+
+        let replaced = unit.upsert(
+            Notification {
+                summary: "replaced-summary".to_string(),
+                timestamp: 5678,
+                updated_at: 5678,
+                ..added_item
+            },
+            false,
+        );
+
+        assert!(replaced, "existing notification is replaced");
+        assert_eq!(unit.count(), 2, "replacing does not add a duplicate");
+
+        let notifications = unit.items();
+        let n = notifications.first().expect("Has added element");
+        assert_eq!(n.id, 1, "replaced notification keeps its position");
+        assert_eq!(n.summary, "replaced-summary");
+        assert_eq!(n.timestamp, 1234, "original timestamp is kept");
+        assert_eq!(n.updated_at, 5678);
+    }
+
+    #[test]
+    fn notification_store_upsert_move_to_end() {
+        let (unit, added_item) = add_single_item();
+        unit.add(Notification {
+            id: 2,
+            ..Default::default()
+        });
+
+        assert!(unit.upsert(added_item, true));
+
+        let ids: Vec<u32> = unit.items().iter().map(|n| n.id).collect();
+        assert_eq!(ids, vec![2, 1], "replaced notification is listed as newest");
+    }
+
+    #[test]
+    fn notification_store_upsert_unknown() {
+        let unit = NotificationStore::init();
+
+        assert!(!unit.upsert(
+            Notification {
+                id: 3,
+                ..Default::default()
+            },
+            false
+        ));
+        assert!(unit.contains(3));
+        assert!(!unit.contains(1));
+    }
+
     fn add_single_item() -> (NotificationStore, Notification) {
         let unit = NotificationStore::init();
 
@@ -256,26 +1027,32 @@ mod tests {
             id: 1,
             summary: "test-summary".to_string(),
             body: "test-body".to_string(),
+            body_plain: "test-body".to_string(),
+            body_pango: "test-body".to_string(),
+            links: Vec::new(),
             application: "test-app".to_string(),
+            app_name: "test-app".to_string(),
+            desktop_entry: None,
             icon: "test-icon".to_string(),
+            icon_path: None,
+            image: None,
+            image_data: None,
             urgency: Urgency::Critical,
-            actions: vec!["test-action-1".to_string()],
+            actions: vec!["default".to_string(), "Open".to_string()],
             hints: HashMap::from([(
                 "test-hint-key-1".to_string(),
-                "test-hint-value-1".to_string(),
+                Hint::String("test-hint-value-1".to_string()),
             )]),
+            resident: false,
+            seen: false,
+            transient: false,
+            skip_history: false,
+            expire_timeout: -1,
             timestamp: 1234,
+            updated_at: 1234,
         };
 
-        let test_notification_copy: Notification = Notification {
-            summary: test_notification.summary.clone(),
-            body: test_notification.body.clone(),
-            application: test_notification.application.clone(),
-            icon: test_notification.icon.clone(),
-            actions: test_notification.actions.clone(),
-            hints: test_notification.hints.clone(),
-            ..test_notification
-        };
+        let test_notification_copy = test_notification.clone();
 
         unit.add(test_notification);
 
@@ -1,17 +1,197 @@
-use std::{os::unix::net::{UnixListener, UnixStream}, io::BufRead, io::{BufReader, BufWriter, Write}};
+use std::{os::unix::net::{UnixListener, UnixStream}, io::BufRead, io::{BufReader, BufWriter, ErrorKind, Read, Write}, fmt::Display, sync::mpsc::{RecvTimeoutError, Sender}, thread, time::Duration};
+use std::fs;
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 use log::{warn, debug, error};
 
-use crate::notification::{NotificationStore, Urgency};
+use crate::notification::{Action, CloseReason, Notification, NotificationStore, StoreEvent, Urgency};
+use serde::Serialize;
+use crate::template::Templates;
+use crate::xdg;
+
+/// File name of the socket, as expected by rofication clients.
+const SOCKET_NAME: &str = "rofi_notification_daemon";
+
+/// Time a client has to send a further command once a response was sent.
+///
+/// Stock rofication clients send one command and read until the connection is
+/// closed, so waiting for the full timeout would stall every one of them.
+const FOLLOW_UP_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Maximum number of clients served at the same time, including the watching ones.
+const MAX_CLIENTS: usize = 64;
+
+/// Time between the checks of a watching client having disconnected, while no event is sent.
+const WATCH_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Returns the default socket path, in `$XDG_RUNTIME_DIR` if it is set.
+pub fn default_socket_path() -> PathBuf {
+    xdg::runtime_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join(SOCKET_NAME)
+}
+
+/// Socket file of a bound server, removed when dropped.
+#[derive(Debug)]
+pub struct SocketFile {
+    path: PathBuf,
+}
+
+impl Drop for SocketFile {
+    fn drop(&mut self) {
+        debug!("Removing socket {}", self.path.display());
+        if let Err(e) = fs::remove_file(&self.path) {
+            warn!("Unable to remove socket {}: {}", self.path.display(), e);
+        }
+    }
+}
 
 /// Provides service to roficiation clients. See https://github.com/DaveDavenport/Rofication
+///
+/// Clients are served concurrently and may send several commands, one per line.
+/// Every response is terminated by a newline. The connection is closed once the
+/// client sends no further command shortly after a response.
+#[derive(Clone)]
 pub struct RofiServer {
-    socket_path: String,
+    socket_path: PathBuf,
     db: NotificationStore,
+    sender: Sender<Action>,
+    timeout: Duration,
+    legacy: bool,
+    templates: Templates,
+    clients: Arc<AtomicUsize>,
+}
+
+/// Place of a client among the [`MAX_CLIENTS`] served ones, given back when dropped.
+struct ClientSlot {
+    clients: Arc<AtomicUsize>,
+}
+
+impl ClientSlot {
+    /// Takes a place, unless all of them are taken.
+    fn take(clients: &Arc<AtomicUsize>) -> Option<Self> {
+        clients
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |count| {
+                (count < MAX_CLIENTS).then_some(count + 1)
+            })
+            .ok()?;
+        Some(Self {
+            clients: Arc::clone(clients),
+        })
+    }
+}
+
+impl Drop for ClientSlot {
+    fn drop(&mut self) {
+        self.clients.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// Outcome of a command, sent back to the client.
+///
+/// In legacy mode only data is sent back, without a newline, and the connection is
+/// closed after the first command, like stock rofication does.
+#[derive(Clone, Debug, PartialEq)]
+pub enum RofiResponse {
+    /// Result of a query, e.g. the count of notifications
+    Data(String),
+    /// The command was executed, sent as `ok`
+    Ok,
+    /// No notification with the id, sent as `error:not-found:<id>`
+    NotFound(u32),
+    /// The request is invalid, sent as `error:parse:<message>`
+    ParseError(String),
+    /// The command could not be executed, sent as `error:failed:<message>`
+    Failed(String),
+}
+
+impl Display for RofiResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RofiResponse::Data(data) => write!(f, "{data}"),
+            RofiResponse::Ok => write!(f, "ok"),
+            RofiResponse::NotFound(id) => write!(f, "error:not-found:{id}"),
+            RofiResponse::ParseError(message) => write!(f, "error:parse:{message}"),
+            RofiResponse::Failed(message) => write!(f, "error:failed:{message}"),
+        }
+    }
+}
+
+/// Notifications a `watch` client is interested in.
+#[derive(Clone, Debug, PartialEq)]
+pub enum WatchFilter {
+    /// All the notifications, sent as `watch`
+    All,
+    /// Critical notifications, sent as `watch:critical`
+    Critical,
+    /// Notifications of an app, sent as `watch:app:<name>`
+    App(String),
+}
+
+impl WatchFilter {
+    /// Returns `true` if the client is interested in the notification.
+    pub fn matches(&self, notification: &Notification) -> bool {
+        match self {
+            WatchFilter::All => true,
+            WatchFilter::Critical => notification.urgency == Urgency::Critical,
+            WatchFilter::App(app) => notification.is_from_app(app),
+        }
+    }
+}
+
+/// Event pushed to `watch` clients, as one JSON object per line, e.g.
+/// `{"event":"counts","count":2,"critical":1,"unseen":2}`.
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "event", rename_all = "lowercase")]
+enum WatchEvent<'a> {
+    /// A notification was added
+    Added { notification: &'a Notification },
+    /// A notification was replaced by its sender
+    Replaced { notification: &'a Notification },
+    /// A notification was removed
+    Removed { id: u32 },
+    /// The number of matching notifications changed, also sent when watching starts
+    Counts {
+        count: usize,
+        critical: usize,
+        unseen: usize,
+    },
+}
+
+/// Numbers of notifications sent to `watch` clients.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Counts {
+    count: usize,
+    critical: usize,
+    unseen: usize,
+}
+
+impl Counts {
+    /// Counts notifications given their urgency and seen state
+    fn of<'a>(notifications: impl Iterator<Item = &'a (Urgency, bool)>) -> Self {
+        notifications.fold(Counts::default(), |counts, (urgency, seen)| Counts {
+            count: counts.count + 1,
+            critical: counts.critical + usize::from(*urgency == Urgency::Critical),
+            unseen: counts.unseen + usize::from(!seen),
+        })
+    }
+
+    fn event(self) -> WatchEvent<'static> {
+        WatchEvent::Counts {
+            count: self.count,
+            critical: self.critical,
+            unseen: self.unseen,
+        }
+    }
 }
 
 /// See https://github.com/DaveDavenport/Rofication/blob/master/rofication-daemon.py#LL155C1-L170C87
 pub enum RofiCommand {
-    /// Retrieve count of notifications
+    /// Retrieve count of notifications and critical notifications, as `<total>,<critical>`
     Count,
     /// Retrieve all notifications
     List,
@@ -21,13 +201,41 @@ pub enum RofiCommand {
     DeleteSimilar(u32),
     /// Delete all notifications with app name
     DeleteApps(String),
-    /// Reduce urgency to 'normal'
+    /// Mark as seen and reduce a 'critical' urgency to 'normal'
     MarkSeen(u32),
+    /// Invoke the action with the given key
+    InvokeAction(u32, String),
+    /// Open the link with the given index, the first one by default
+    OpenLink(u32, usize),
+    /// Pause ('on') or resume ('off') delivery, toggle by default
+    Pause(Option<bool>),
+    /// Render the notifications with the named template, the default one by default
+    ///
+    /// The reply starts with the number of notifications on its own line, followed by one line each,
+    /// so that clients know where it ends without waiting for the connection to close.
+    Render(Option<String>),
+    /// Keep the connection open and push the changes of the matching notifications
+    Watch(WatchFilter),
 }
 
+/// Commands understood by the server.
+const COMMANDS: [&str; 11] = ["num", "list", "del", "dels", "dela", "saw", "act", "open", "pause", "render", "watch"];
+
 impl RofiCommand {
-    fn parse(client_request: &str) -> Option<RofiCommand> {
-        let mut token_iter = client_request.split(":").into_iter();
+    /// Parses a request, describing the problem if it is invalid
+    fn parse(client_request: &str) -> Result<RofiCommand, String> {
+        Self::parse_command(client_request).ok_or_else(|| {
+            let command = client_request.split(':').next().unwrap_or_default();
+            if COMMANDS.contains(&command) {
+                format!("invalid arguments for '{command}'")
+            } else {
+                format!("unknown command '{command}'")
+            }
+        })
+    }
+
+    fn parse_command(client_request: &str) -> Option<RofiCommand> {
+        let mut token_iter = client_request.split(':');
 
         match token_iter.next() {
             Some(command) => {
@@ -52,10 +260,15 @@ impl RofiCommand {
                     },
                     "dela" => {
                         let app_name = token_iter
-                            .next()?
+                            .collect::<Vec<&str>>()
+                            .join(":")
                             .trim()
                             .to_string();
 
+                        if app_name.is_empty() {
+                            return None;
+                        }
+
                         Some(Self::DeleteApps(app_name))
                     },
                     "saw" => {
@@ -66,10 +279,74 @@ impl RofiCommand {
 
                         Some(Self::MarkSeen(id))
                     },
-                    unrecognized_cmd => {
-                        warn!("unknown command: '{}'", unrecognized_cmd);
-                        None
-                    }
+                    "act" => {
+                        let id = token_iter
+                            .next()?
+                            .parse::<u32>()
+                            .ok()?;
+                        let key = token_iter
+                            .collect::<Vec<&str>>()
+                            .join(":");
+
+                        if key.is_empty() {
+                            return None;
+                        }
+
+                        Some(Self::InvokeAction(id, key))
+                    },
+                    "open" => {
+                        let id = token_iter
+                            .next()?
+                            .parse::<u32>()
+                            .ok()?;
+                        let index = match token_iter.next() {
+                            Some(index) => index.parse::<usize>().ok()?,
+                            None => 0,
+                        };
+
+                        Some(Self::OpenLink(id, index))
+                    },
+                    "pause" => {
+                        let paused = match token_iter.next().map(str::trim) {
+                            Some("on") => Some(true),
+                            Some("off") => Some(false),
+                            Some("toggle") | None => None,
+                            Some(_) => return None,
+                        };
+
+                        Some(Self::Pause(paused))
+                    },
+                    "render" => {
+                        let name = match token_iter.next().map(str::trim) {
+                            Some("") => return None,
+                            name => name.map(String::from),
+                        };
+
+                        Some(Self::Render(name))
+                    },
+                    "watch" => {
+                        let filter = match token_iter.next().map(str::trim) {
+                            None => WatchFilter::All,
+                            Some("critical") if token_iter.next().is_none() => WatchFilter::Critical,
+                            Some("app") => {
+                                let app_name = token_iter
+                                    .collect::<Vec<&str>>()
+                                    .join(":")
+                                    .trim()
+                                    .to_string();
+
+                                if app_name.is_empty() {
+                                    return None;
+                                }
+
+                                WatchFilter::App(app_name)
+                            },
+                            Some(_) => return None,
+                        };
+
+                        Some(Self::Watch(filter))
+                    },
+                    _ => None,
                 }
 
             },
@@ -78,80 +355,784 @@ impl RofiCommand {
     }
 }
 
-impl  RofiServer {
-    /// Create a new server instance
-    pub fn new(socket_path: String, db: NotificationStore) -> RofiServer {
-        return RofiServer { socket_path, db }
+impl RofiServer {
+    /// Create a new server instance, closing client connections that are idle for longer than `timeout`
+    ///
+    /// In `legacy` mode, acknowledgements and errors are not sent back.
+    /// The `render` command renders the notifications with the `templates`.
+    pub fn new(
+        socket_path: PathBuf,
+        db: NotificationStore,
+        sender: Sender<Action>,
+        timeout: Duration,
+        legacy: bool,
+        templates: Templates,
+    ) -> RofiServer {
+        RofiServer { socket_path, db, sender, timeout, legacy, templates, clients: Arc::new(AtomicUsize::new(0)) }
     }
 
-    /// Server listens for incoming requests, blocks
-    pub fn start(&self) -> std::io::Result<()> {
-        debug!("Rofication server binding to path {}", &self.socket_path);
-        let listener = UnixListener::bind(&self.socket_path)?;
-    
+    /// Binds the socket, only accessible by the user.
+    ///
+    /// A stale socket left behind by a crashed instance is replaced, but a socket
+    /// another server listens on is not. The socket is removed when the returned
+    /// [`SocketFile`] is dropped.
+    ///
+    /// The socket is bound in a private directory and only moved into place once its
+    /// permissions are set, so other users never get a chance to connect.
+    pub fn bind(&self) -> std::io::Result<(UnixListener, SocketFile)> {
+        debug!("Rofication server binding to path {}", self.socket_path.display());
+        remove_stale_socket(&self.socket_path)?;
+
+        let name = self.socket_path.file_name().unwrap_or_default();
+        let mut private_name = std::ffi::OsString::from(".");
+        private_name.push(name);
+        private_name.push(format!(".{}", std::process::id()));
+        let private_dir = self.socket_path.with_file_name(private_name);
+        if private_dir.exists() {
+            fs::remove_dir_all(&private_dir)?;
+        }
+        fs::DirBuilder::new().mode(0o700).create(&private_dir)?;
+
+        let bound = bind_private(&private_dir.join(name), &self.socket_path);
+        fs::remove_dir_all(&private_dir)?;
+        let listener = bound?;
+
+        let socket = SocketFile {
+            path: self.socket_path.clone(),
+        };
+        Ok((listener, socket))
+    }
+
+    /// Server listens for incoming requests and serves each client on its own thread, blocks
+    ///
+    /// Clients beyond [`MAX_CLIENTS`] are turned away.
+    pub fn start(&self, listener: UnixListener) {
         for stream in listener.incoming() {
             match stream {
                 Ok(stream) => {
-                    self.handle_request(stream);                    
-                }
-                Err(err) => {
-                    println!("Failed to initialize socket listener: {}", err);
-                    break;
+                    let slot = match ClientSlot::take(&self.clients) {
+                        Some(slot) => slot,
+                        None => {
+                            warn!("Too many rofication clients, closing connection");
+                            continue;
+                        }
+                    };
+                    let server = self.clone();
+                    let client = thread::Builder::new()
+                        .name("rofication-client".to_string())
+                        .spawn(move || {
+                            server.handle_client(stream);
+                            drop(slot);
+                        });
+                    if let Err(e) = client {
+                        error!("Unable to serve rofication client: {}", e);
+                    }
                 }
+                Err(e) => warn!("Unable to accept rofication client: {}", e),
             }
         }
-        Ok(())
     }
 
-    fn handle_request(&self, stream: UnixStream) {        
-        let mut client_in = BufReader::new(&stream);
-        let mut client_out = BufWriter::new(&stream);
+    /// Serves the commands of a client, one per line, until it disconnects or times out
+    fn handle_client(&self, stream: UnixStream) {
+        if let Err(e) = self.serve(&stream) {
+            match e.kind() {
+                ErrorKind::WouldBlock | ErrorKind::TimedOut => debug!("Rofication client timed out"),
+                _ => warn!("Rofication client failed: {}", e),
+            }
+        }
+    }
+
+    fn serve(&self, stream: &UnixStream) -> std::io::Result<()> {
+        stream.set_read_timeout(Some(self.timeout))?;
+        stream.set_write_timeout(Some(self.timeout))?;
+        let mut client_in = BufReader::new(stream);
+        let mut client_out = BufWriter::new(stream);
 
         let mut line = String::new();
-        let _ = client_in.read_line(&mut line).expect("unable to read");
+        let mut responded = false;
+        loop {
+            line.clear();
+            match client_in.read_line(&mut line) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                // The client is done once it sends nothing after a response.
+                Err(e) if responded && matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    return Ok(())
+                }
+                Err(e) => return Err(e),
+            }
 
-        let line = line.trim();
-        debug!("Rofication client request: '{}'", line);
+            let request = line.trim();
+            if request.is_empty() {
+                continue;
+            }
+            debug!("Rofication client request: '{}'", request);
+
+            let response = match RofiCommand::parse(request) {
+                Ok(RofiCommand::Watch(filter)) => return self.watch(&filter, &mut client_in, &mut client_out),
+                Ok(command) => self.execute_command(command),
+                Err(message) => {
+                    error!("Unable to parse message, no action taken: {}", request);
+                    RofiResponse::ParseError(message)
+                }
+            };
+            if self.legacy {
+                // Like stock rofication: the bare data, if any, then the connection is closed.
+                if let RofiResponse::Data(data) = response {
+                    client_out.write_all(data.as_bytes())?;
+                    client_out.flush()?;
+                }
+                return Ok(());
+            }
+            respond(&mut client_out, &response.to_string())?;
+            if !responded {
+                responded = true;
+                stream.set_read_timeout(Some(FOLLOW_UP_TIMEOUT.min(self.timeout)))?;
+            }
+        }
+    }
+
+    /// Pushes the changes of the notifications matching the filter, until the client disconnects
+    ///
+    /// The connection is closed as well if the client lags too far behind the events,
+    /// it then has to watch again to catch up.
+    fn watch(
+        &self,
+        filter: &WatchFilter,
+        client_in: &mut BufReader<&UnixStream>,
+        client_out: &mut BufWriter<&UnixStream>,
+    ) -> std::io::Result<()> {
+        debug!("Rofication client watches {:?}", filter);
+        let (notifications, events) = self.db.subscribe();
+        // Urgency and seen state of the matching notifications, by id, as of the last event.
+        let mut matching: BTreeMap<u32, (Urgency, bool)> = notifications
+            .iter()
+            .filter(|n| filter.matches(n))
+            .map(|n| (n.id, (n.urgency, n.seen)))
+            .collect();
+        let mut counts = Counts::of(matching.values());
+        respond(client_out, &serde_json::to_string(&counts.event())?)?;
 
-        match RofiCommand::parse(&line) {
-            Some(command) => self.execute_command(command, &mut client_out),
-            None => error!("Unable to parse message, no action taken: {}", &line),
+        // Reads only check whether the client is gone, so they must not wait.
+        client_in.get_ref().set_read_timeout(Some(Duration::from_millis(1)))?;
+        loop {
+            let event = match events.recv_timeout(WATCH_POLL_INTERVAL) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) if disconnected(client_in)? => return Ok(()),
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => {
+                    warn!("Rofication client lagged behind the events, closing the watch");
+                    return Ok(());
+                }
+            };
+            let notification = event.notification();
+            let pushed = match &event {
+                StoreEvent::Added(notification) => Some(WatchEvent::Added { notification }),
+                StoreEvent::Replaced(notification) => Some(WatchEvent::Replaced { notification }),
+                StoreEvent::Removed(notification) => Some(WatchEvent::Removed { id: notification.id }),
+                StoreEvent::Changed(_) => None,
+            };
+            let matches = filter.matches(notification);
+            if let Some(pushed) = pushed.filter(|_| matches) {
+                respond(client_out, &serde_json::to_string(&pushed)?)?;
+            }
+
+            if matches && !matches!(event, StoreEvent::Removed(_)) {
+                matching.insert(notification.id, (notification.urgency, notification.seen));
+            } else {
+                matching.remove(&notification.id);
+            }
+            let current = Counts::of(matching.values());
+            if current != counts {
+                counts = current;
+                respond(client_out, &serde_json::to_string(&counts.event())?)?;
+            }
         }
     }
 
-    fn execute_command(&self, cmd: RofiCommand, client_out: &mut BufWriter<&UnixStream>) {
+    fn execute_command(&self, cmd: RofiCommand) -> RofiResponse {
         match cmd {
-            RofiCommand::Count => {                
-                client_out.write(self.db.count().to_string().as_bytes()).unwrap();
-                client_out.flush().expect("Sending response back to client")
+            RofiCommand::Count => {
+                RofiResponse::Data(format!("{},{}", self.db.count(), self.db.critical_count()))
             },
             RofiCommand::List => {
                 let elems = self.db.items();
-                let response = serde_json::to_string(&elems).unwrap();
-                client_out.write(&response.as_bytes()).unwrap();
-                client_out.flush().expect("Sending response back to client")
+                match serde_json::to_string(&elems) {
+                    Ok(response) => RofiResponse::Data(response),
+                    Err(e) => {
+                        error!("Unable to serialize notifications: {}", e);
+                        RofiResponse::Failed(e.to_string())
+                    }
+                }
             },
             RofiCommand::DeleteOne(id) => {
-                self.db.delete(id);
+                if !self.db.delete(id) {
+                    return RofiResponse::NotFound(id);
+                }
+                self.dismissed(vec![id])
             },
             RofiCommand::DeleteApps(app_name) => {
-                self.db.delete_from_app(app_name);
+                let ids = self.db.delete_from_app(app_name);
+                self.dismissed(ids)
             },
             RofiCommand::DeleteSimilar(id) => {
-                let notifications = self.db.items();
-                let source_notification = notifications.iter().find(|n| n.id == id);       
+                let ids = self.db.delete_similar(id);
+                if ids.is_empty() {
+                    return RofiResponse::NotFound(id);
+                }
+                self.dismissed(ids)
+            },
+            RofiCommand::MarkSeen(id) => {
+                if !self.db.mark_seen(id) {
+                    return RofiResponse::NotFound(id);
+                }
+                self.send(Action::Seen(id))
+            },
+            RofiCommand::InvokeAction(id, key) => {
+                match self.db.get(id) {
+                    Some(notification) if notification.has_action(&key) => self.send(Action::Invoke(id, key)),
+                    Some(_) => RofiResponse::Failed(format!("no action '{key}'")),
+                    None => RofiResponse::NotFound(id),
+                }
+            },
+            RofiCommand::OpenLink(id, index) => {
+                match self.db.get(id) {
+                    Some(notification) if index < notification.links.len() => self.send(Action::OpenLink(id, index)),
+                    Some(_) => RofiResponse::Failed(format!("no link {index}")),
+                    None => RofiResponse::NotFound(id),
+                }
+            },
+            RofiCommand::Pause(paused) => {
+                match Action::request(&self.sender, |reply| Action::Pause(paused, reply)) {
+                    Some(paused) => RofiResponse::Data(if paused { "1" } else { "0" }.to_string()),
+                    None => {
+                        error!("Unable to change paused state");
+                        RofiResponse::Failed("main loop is not running".to_string())
+                    },
+                }
+            },
+            RofiCommand::Watch(_) => {
+                RofiResponse::Failed("watch is only served on the connection".to_string())
+            },
+            RofiCommand::Render(name) => {
+                let template = match self.templates.get(name.as_deref()) {
+                    Some(template) => template,
+                    None => return RofiResponse::Failed(format!("no template '{}'", name.unwrap_or_default())),
+                };
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |now| now.as_secs());
+                let lines = self
+                    .db
+                    .items()
+                    .iter()
+                    .map(|notification| self.templates.render(template, notification, now))
+                    .collect::<crate::error::Result<Vec<String>>>();
+                match lines {
+                    Ok(lines) => RofiResponse::Data(
+                        std::iter::once(lines.len().to_string()).chain(lines).collect::<Vec<_>>().join("\n"),
+                    ),
+                    Err(e) => {
+                        error!("Unable to render notifications: {}", e);
+                        RofiResponse::Failed(e.to_string().replace('\n', " "))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Passes an action on to the main loop
+    fn send(&self, action: Action) -> RofiResponse {
+        match self.sender.send(action) {
+            Ok(()) => RofiResponse::Ok,
+            Err(e) => {
+                error!("Unable to pass action on to main loop: {}", e);
+                RofiResponse::Failed("main loop is not running".to_string())
+            }
+        }
+    }
+
+    /// Reports notifications deleted by the client as dismissed by the user
+    fn dismissed(&self, ids: Vec<u32>) -> RofiResponse {
+        if ids.is_empty() {
+            return RofiResponse::Ok;
+        }
+        self.send(Action::Closed(ids, CloseReason::Dismissed))
+    }
+}
+
+/// Removes the socket at the path if no server listens on it anymore
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(std::io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} exists and is not a socket", path.display()),
+        ));
+    }
+    if UnixStream::connect(path).is_ok() {
+        return Err(std::io::Error::new(
+            ErrorKind::AddrInUse,
+            format!("another server listens on {}", path.display()),
+        ));
+    }
+    warn!("Removing stale socket {}", path.display());
+    fs::remove_file(path)
+}
+
+/// Binds a socket at a private path, restricts it to the user and moves it to its final path
+fn bind_private(private_path: &Path, path: &Path) -> std::io::Result<UnixListener> {
+    let listener = UnixListener::bind(private_path)?;
+    fs::set_permissions(private_path, fs::Permissions::from_mode(0o600))?;
+    fs::rename(private_path, path)?;
+    Ok(listener)
+}
 
-                if let Some(source_notification) = source_notification {
-                    let app_name = source_notification.application.clone();
+/// Returns `true` if the client closed the connection, discarding anything it sent.
+fn disconnected(client_in: &mut BufReader<&UnixStream>) -> std::io::Result<bool> {
+    let mut discarded = [0; 256];
+    match client_in.read(&mut discarded) {
+        Ok(read) => Ok(read == 0),
+        Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => Ok(false),
+        Err(e) => Err(e),
+    }
+}
 
-                    if !app_name.is_empty() {
-                        self.db.delete_from_app(app_name);
+/// Sends a response back to the client, terminated by a newline
+fn respond(client_out: &mut BufWriter<&UnixStream>, response: &str) -> std::io::Result<()> {
+    client_out.write_all(response.as_bytes())?;
+    client_out.write_all(b"\n")?;
+    client_out.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notification::{Notification, Urgency};
+    use crate::template::TimeZone;
+    use serde_json::Value;
+    use std::io::Read;
+    use std::net::Shutdown;
+    use std::path::PathBuf;
+    use std::sync::mpsc;
+    use std::time::Instant;
+
+    fn templates() -> Templates {
+        let named = [("app".to_string(), "{app}".to_string())].into_iter().collect();
+        Templates::new("{id} {summary} ({urgency})", &named, TimeZone::Fixed(time::UtcOffset::UTC))
+            .expect("Parses templates")
+    }
+
+    /// Rofication server on a temporary socket, with a stand-in for the main loop.
+    struct Harness {
+        socket_path: PathBuf,
+        db: NotificationStore,
+        clients: Arc<AtomicUsize>,
+        _socket: SocketFile,
+    }
+
+    impl Harness {
+        fn start(name: &str, legacy: bool) -> Self {
+            let socket_path = std::env::temp_dir().join(format!("armesto-rofi-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_file(&socket_path);
+            let db = NotificationStore::init();
+            let (sender, receiver) = mpsc::channel();
+
+            let main_db = db.clone();
+            thread::spawn(move || {
+                for action in receiver {
+                    if let Action::Pause(paused, reply) = action {
+                        main_db.set_paused(paused.unwrap_or(!main_db.is_paused()));
+                        let _ = reply.send(main_db.is_paused());
                     }
                 }
-            },
-            RofiCommand::MarkSeen(id) => {
-                self.db.set_urgency(id, Urgency::Normal);
+            });
+
+            let server = RofiServer::new(
+                socket_path.clone(),
+                db.clone(),
+                sender,
+                Duration::from_secs(5),
+                legacy,
+                templates(),
+            );
+            let (listener, socket) = server.bind().expect("Binds socket");
+            let clients = Arc::clone(&server.clients);
+            thread::spawn(move || server.start(listener));
+
+            Self {
+                socket_path,
+                db,
+                clients,
+                _socket: socket,
+            }
+        }
+
+        fn clients(&self) -> usize {
+            self.clients.load(Ordering::SeqCst)
+        }
+
+        fn add(&self, id: u32, application: &str, urgency: Urgency) {
+            self.db.add(Notification {
+                id,
+                summary: format!("summary {id}"),
+                body: format!("body {id}"),
+                application: application.to_string(),
+                app_name: application.to_string(),
+                icon: "mail-unread".to_string(),
+                urgency,
+                actions: vec!["default".to_string(), "Open".to_string()],
+                timestamp: 1_700_000_000,
+                updated_at: 1_700_000_000,
+                ..Default::default()
+            });
+        }
+
+        fn connect(&self) -> UnixStream {
+            for _ in 0..100 {
+                if let Ok(stream) = UnixStream::connect(&self.socket_path) {
+                    return stream;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+            panic!("Server is not listening");
+        }
+
+        /// Sends the requests on one connection and returns the response lines.
+        fn request(&self, requests: &str) -> Vec<String> {
+            self.request_raw(requests).lines().map(String::from).collect()
+        }
+
+        /// Sends the requests on one connection and returns everything sent back.
+        fn request_raw(&self, requests: &str) -> String {
+            let mut client = self.connect();
+            client.write_all(requests.as_bytes()).expect("Sends commands");
+            client.shutdown(Shutdown::Write).expect("Closes for writing");
+            let mut response = String::new();
+            client.read_to_string(&mut response).expect("Reads responses");
+            response
+        }
+    }
+
+    #[test]
+    fn rofi_command_parse_errors() {
+        assert!(matches!(RofiCommand::parse("del:3"), Ok(RofiCommand::DeleteOne(3))));
+        assert_eq!(
+            RofiCommand::parse("del:x").err(),
+            Some("invalid arguments for 'del'".to_string())
+        );
+        assert_eq!(
+            RofiCommand::parse("nope").err(),
+            Some("unknown command 'nope'".to_string())
+        );
+        assert_eq!(
+            RofiCommand::parse("dela:").err(),
+            Some("invalid arguments for 'dela'".to_string())
+        );
+        assert!(matches!(
+            RofiCommand::parse("watch:app:org.app:main"),
+            Ok(RofiCommand::Watch(WatchFilter::App(app))) if app == "org.app:main"
+        ));
+        for request in ["watch:app", "watch:critical:1", "watch:low"] {
+            assert_eq!(
+                RofiCommand::parse(request).err(),
+                Some("invalid arguments for 'watch'".to_string()),
+                "{request}"
+            );
+        }
+    }
+
+    #[test]
+    fn rofi_server_socket_lifecycle() {
+        let socket_path = std::env::temp_dir().join(format!("armesto-rofi-bind-{}", std::process::id()));
+        let _ = fs::remove_file(&socket_path);
+        let (sender, _receiver) = mpsc::channel();
+        let unit = RofiServer::new(
+            socket_path.clone(),
+            NotificationStore::init(),
+            sender,
+            Duration::from_secs(1),
+            false,
+            templates(),
+        );
+
+        drop(UnixListener::bind(&socket_path).expect("Binds stale socket"));
+        let (listener, socket) = unit.bind().expect("Replaces stale socket");
+        let mode = fs::metadata(&socket_path).expect("Socket exists").permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let private_dir = socket_path.with_file_name(format!(
+            ".armesto-rofi-bind-{}.{}",
+            std::process::id(),
+            std::process::id()
+        ));
+        assert!(!private_dir.exists(), "private directory is removed");
+
+        let error = unit.bind().expect_err("Socket is in use");
+        assert_eq!(error.kind(), ErrorKind::AddrInUse);
+        assert!(socket_path.exists(), "socket in use is kept");
+
+        drop(listener);
+        drop(socket);
+        assert!(!socket_path.exists(), "socket is removed");
+
+        fs::write(&socket_path, "").expect("Writes file");
+        assert_eq!(unit.bind().expect_err("File is kept").kind(), ErrorKind::AlreadyExists);
+        fs::remove_file(&socket_path).expect("Removes file");
+    }
+
+    #[test]
+    fn rofi_server_serves_clients_concurrently() {
+        let unit = Harness::start("concurrent", false);
+        unit.add(1, "mail", Urgency::Normal);
+
+        let _stalled = unit.connect();
+
+        let lines = unit.request("num\nbogus\nlist\ndel:7\nnum\n");
+        assert_eq!(lines.len(), 5, "one response per command: {lines:?}");
+        assert_eq!(lines[0], "1,0");
+        assert_eq!(lines[1], "error:parse:unknown command 'bogus'");
+        assert!(lines[2].starts_with(r#"[{"id":1,"#));
+        assert_eq!(lines[3], "error:not-found:7");
+        assert_eq!(lines[4], "1,0");
+    }
+
+    #[test]
+    fn rofi_server_limits_clients() {
+        let unit = Harness::start("limit", false);
+        let idle: Vec<UnixStream> = (0..MAX_CLIENTS).map(|_| unit.connect()).collect();
+
+        let mut turned_away = unit.connect();
+        let mut response = String::new();
+        turned_away.read_to_string(&mut response).expect("Reads until closed");
+        assert_eq!(response, "", "client beyond the limit is closed");
+
+        drop(idle);
+        for _ in 0..100 {
+            if unit.clients() == 0 {
+                break;
             }
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(unit.request("num\n"), vec!["0,0"], "places are given back");
+    }
+
+    #[test]
+    fn rofication_reference_client_reads_until_closed() {
+        for legacy in [false, true] {
+            let unit = Harness::start(&format!("reference-{legacy}"), legacy);
+            unit.add(1, "mail", Urgency::Critical);
+
+            // Like the reference client: send one command, then read until EOF without closing for writing.
+            let started = Instant::now();
+            let mut client = unit.connect();
+            client.write_all(b"num\n").expect("Sends command");
+            let mut response = String::new();
+            client.read_to_string(&mut response).expect("Reads until closed");
+
+            assert_eq!(response, if legacy { "1,1" } else { "1,1\n" });
+            assert!(
+                started.elapsed() < Duration::from_secs(1),
+                "closed well before the socket timeout, after {:?}",
+                started.elapsed()
+            );
         }
     }
+
+    #[test]
+    fn rofication_num_counts_total_and_critical() {
+        let unit = Harness::start("num", false);
+        assert_eq!(unit.request("num"), vec!["0,0"], "request without newline");
+
+        unit.add(1, "mail", Urgency::Normal);
+        unit.add(2, "chat", Urgency::Critical);
+        unit.add(3, "chat", Urgency::Critical);
+
+        assert_eq!(unit.request("num\n"), vec!["3,2"]);
+    }
+
+    #[test]
+    fn rofication_list_fields() {
+        let unit = Harness::start("list", false);
+        unit.add(1, "mail", Urgency::Low);
+        unit.add(2, "chat", Urgency::Critical);
+
+        let lines = unit.request("list\n");
+        let list: Value = serde_json::from_str(&lines[0]).expect("List is JSON");
+        let items = list.as_array().expect("List is an array");
+        assert_eq!(items.len(), 2);
+
+        let first = &items[0];
+        assert_eq!(first["id"], 1);
+        assert_eq!(first["summary"], "summary 1");
+        assert_eq!(first["body"], "body 1");
+        assert_eq!(first["application"], "mail");
+        assert_eq!(first["icon"], "mail-unread");
+        assert_eq!(first["urgency"], 0, "urgency is a number");
+        assert_eq!(first["actions"], serde_json::json!(["default", "Open"]));
+        assert!(first["hints"].is_object());
+        assert_eq!(first["timestamp"], 1_700_000_000);
+        assert_eq!(items[1]["urgency"], 2);
+        assert!(
+            first.as_object().expect("Item is an object").values().all(|v| !v.is_null()),
+            "unset fields are omitted"
+        );
+    }
+
+    #[test]
+    fn rofication_saw_reduces_critical_urgency() {
+        let unit = Harness::start("saw", false);
+        unit.add(1, "mail", Urgency::Low);
+        unit.add(2, "chat", Urgency::Critical);
+
+        let lines = unit.request("saw:1\nsaw:2\nsaw:9\nnum\n");
+        assert_eq!(lines, vec!["ok", "ok", "error:not-found:9", "2,0"]);
+
+        assert_eq!(unit.db.get(1).map(|n| n.urgency), Some(Urgency::Low));
+        assert_eq!(unit.db.get(2).map(|n| n.urgency), Some(Urgency::Normal));
+        assert_eq!(unit.db.unseen_count(), 0);
+    }
+
+    #[test]
+    fn rofication_delete_commands() {
+        let unit = Harness::start("delete", false);
+        unit.add(1, "mail", Urgency::Normal);
+        unit.add(2, "chat", Urgency::Normal);
+        unit.add(3, "chat", Urgency::Normal);
+        unit.add(4, "org.app:main", Urgency::Normal);
+        unit.add(5, "other", Urgency::Normal);
+
+        let lines = unit.request("del:1\ndel:1\ndels:2\ndela:org.app:main\ndela:missing\nnum\n");
+        assert_eq!(lines, vec!["ok", "error:not-found:1", "ok", "ok", "ok", "1,0"]);
+        assert_eq!(unit.db.items().iter().map(|n| n.id).collect::<Vec<u32>>(), vec![5]);
+    }
+
+    #[test]
+    fn rofication_errors() {
+        let unit = Harness::start("errors", false);
+        unit.add(1, "mail", Urgency::Normal);
+
+        let lines = unit.request("del\ndel:x\nfoo:1\nact:1:missing\nact:2:default\nopen:1\n");
+        assert_eq!(
+            lines,
+            vec![
+                "error:parse:invalid arguments for 'del'",
+                "error:parse:invalid arguments for 'del'",
+                "error:parse:unknown command 'foo'",
+                "error:failed:no action 'missing'",
+                "error:not-found:2",
+                "error:failed:no link 0",
+            ]
+        );
+    }
+
+    #[test]
+    fn rofication_legacy_mode() {
+        let unit = Harness::start("legacy", true);
+        unit.add(1, "mail", Urgency::Critical);
+
+        assert_eq!(unit.request_raw("num\nnum\n"), "1,1", "bare data of the first command");
+        assert_eq!(unit.request_raw("bogus\n"), "", "no errors");
+        assert_eq!(unit.request_raw("del:1\nnum\n"), "", "no acknowledgements");
+        assert_eq!(unit.request_raw("num"), "0,0");
+    }
+
+    #[test]
+    fn rofication_watch_pushes_events() {
+        let unit = Harness::start("watch", false);
+        unit.add(1, "mail", Urgency::Critical);
+
+        let watch = |request: &str| {
+            let mut client = unit.connect();
+            client.write_all(request.as_bytes()).expect("Sends command");
+            client.set_read_timeout(Some(Duration::from_secs(5))).expect("Sets timeout");
+            let mut events = BufReader::new(client);
+            move || -> Value {
+                let mut line = String::new();
+                events.read_line(&mut line).expect("Reads event");
+                serde_json::from_str(&line).expect("Event is JSON")
+            }
+        };
+        let mut all = watch("watch\n");
+        let mut critical = watch("watch:critical\n");
+        let mut chat = watch("watch:app:chat\n");
+        assert_eq!(all(), serde_json::json!({"event": "counts", "count": 1, "critical": 1, "unseen": 1}));
+        assert_eq!(critical()["count"], 1);
+        assert_eq!(chat()["count"], 0);
+
+        unit.add(2, "chat", Urgency::Normal);
+        unit.db.mark_seen(1);
+        unit.db.upsert(
+            Notification {
+                id: 2,
+                summary: "replaced".to_string(),
+                application: "chat".to_string(),
+                ..Default::default()
+            },
+            false,
+        );
+        unit.db.delete(2);
+
+        let added = all();
+        assert_eq!(added["event"], "added");
+        assert_eq!(added["notification"]["id"], 2);
+        assert_eq!(all()["count"], 2);
+        assert_eq!(all(), serde_json::json!({"event": "counts", "count": 2, "critical": 0, "unseen": 1}));
+        assert_eq!(all()["notification"]["summary"], "replaced");
+        assert_eq!(all(), serde_json::json!({"event": "removed", "id": 2}));
+        assert_eq!(all()["count"], 1);
+
+        assert_eq!(critical(), serde_json::json!({"event": "counts", "count": 0, "critical": 0, "unseen": 0}));
+
+        assert_eq!(chat()["event"], "added");
+        assert_eq!(chat()["count"], 1);
+        assert_eq!(chat()["event"], "replaced");
+        assert_eq!(chat()["event"], "removed");
+        assert_eq!(chat()["count"], 0);
+    }
+
+    #[test]
+    fn rofication_watch_ends_on_disconnect() {
+        let unit = Harness::start("watch-gone", false);
+        let mut client = unit.connect();
+        client.write_all(b"watch\n").expect("Sends command");
+        let mut counts = String::new();
+        BufReader::new(&client).read_line(&mut counts).expect("Reads counts");
+        assert_eq!(unit.clients(), 1);
+
+        drop(client);
+        let deadline = Instant::now() + WATCH_POLL_INTERVAL * 5;
+        while unit.clients() > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(unit.clients(), 0, "released without any event");
+    }
+
+    #[test]
+    fn rofication_render_lines() {
+        let unit = Harness::start("render", false);
+        assert_eq!(unit.request("render\n"), vec!["0"], "no notifications");
+
+        unit.add(1, "mail", Urgency::Low);
+        unit.add(2, "chat", Urgency::Critical);
+
+        let lines = unit.request("render\nrender:app\nrender:missing\nrender:\n");
+        assert_eq!(
+            lines,
+            vec![
+                "2",
+                "1 summary 1 (low)",
+                "2 summary 2 (critical)",
+                "2",
+                "mail",
+                "chat",
+                "error:failed:no template 'missing'",
+                "error:parse:invalid arguments for 'render'",
+            ]
+        );
+    }
 }
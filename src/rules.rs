@@ -0,0 +1,316 @@
+use crate::hint::Hint;
+use crate::notification::{Notification, Urgency};
+use regex::Regex;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::collections::BTreeMap;
+
+/// Expected value of a hint, compared by value regardless of the D-Bus integer type.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+pub enum HintValue {
+    /// boolean
+    Bool(bool),
+    /// integer
+    Integer(i64),
+    /// floating point number
+    Float(f64),
+    /// string
+    String(String),
+}
+
+impl HintValue {
+    /// Returns `true` if the hint holds this value.
+    pub fn matches(&self, hint: &Hint) -> bool {
+        match (self, hint) {
+            (HintValue::Bool(expected), Hint::Bool(value)) => expected == value,
+            (HintValue::Integer(expected), Hint::Int(value)) => expected == value,
+            (HintValue::Integer(expected), Hint::UInt(_) | Hint::Byte(_)) => {
+                u64::try_from(*expected).ok() == hint.as_u64()
+            }
+            (HintValue::Float(expected), Hint::Double(value)) => expected == value,
+            (HintValue::String(expected), Hint::String(value)) => expected == value,
+            _ => false,
+        }
+    }
+}
+
+/// Replacement of the matches of a pattern in a text.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Rewrite {
+    /// pattern to replace
+    #[serde(deserialize_with = "regex")]
+    pub pattern: Regex,
+    /// text replacing every match, where `$1` or `${name}` insert the groups of the match
+    #[serde(default)]
+    pub replacement: String,
+}
+
+/// Rule matching incoming notifications and changing them.
+///
+/// Rules are listed in the `[[rules]]` tables of the configuration file.
+/// Patterns use the [regex](https://docs.rs/regex/latest/regex/#syntax) syntax.
+/// A rule applies when all its conditions hold, e.g.
+///
+/// ```toml
+/// [[rules]]
+/// name = "quiet builds"
+/// app_name = "^ci-notifier$"
+/// summary = "(?i)build (passed|succeeded)"
+/// set_urgency = "low"
+/// expire_timeout = 3000
+/// rewrite_summary = { pattern = "^\\[CI\\] ", replacement = "" }
+///
+/// [[rules]]
+/// category = "im.received"
+/// hints = { "x-muted" = true }
+/// drop = true
+/// ```
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Rule {
+    /// name of the rule, shown in the logs
+    pub name: Option<String>,
+
+    /// pattern matching the name of the sending app or its desktop entry
+    #[serde(deserialize_with = "optional_regex")]
+    pub app_name: Option<Regex>,
+    /// pattern matching the summary
+    #[serde(deserialize_with = "optional_regex")]
+    pub summary: Option<Regex>,
+    /// pattern matching the body, including its markup
+    #[serde(deserialize_with = "optional_regex")]
+    pub body: Option<Regex>,
+    /// value of the `category` hint
+    pub category: Option<String>,
+    /// urgency, by name
+    #[serde(deserialize_with = "urgency_by_name")]
+    pub urgency: Option<Urgency>,
+    /// values of hints
+    pub hints: BTreeMap<String, HintValue>,
+
+    /// whether the notification is discarded, ending the evaluation
+    pub drop: bool,
+    /// urgency given to the notification, by name
+    #[serde(deserialize_with = "urgency_by_name")]
+    pub set_urgency: Option<Urgency>,
+    /// rewrite of the summary
+    pub rewrite_summary: Option<Rewrite>,
+    /// rewrite of the body
+    pub rewrite_body: Option<Rewrite>,
+    /// expiration timeout in milliseconds, -1 for the server default
+    pub expire_timeout: Option<i32>,
+    /// whether the notification is transient, i.e. never saved
+    pub transient: Option<bool>,
+    /// whether the notification is left out of the history once removed
+    pub skip_history: bool,
+}
+
+impl Rule {
+    /// Returns `true` if the notification meets all the conditions.
+    pub fn matches(&self, notification: &Notification) -> bool {
+        let matches = |pattern: &Option<Regex>, text: &str| {
+            pattern.as_ref().map_or(true, |pattern| pattern.is_match(text))
+        };
+        let from_app = self.app_name.as_ref().map_or(true, |pattern| {
+            pattern.is_match(&notification.application)
+                || notification
+                    .desktop_entry
+                    .as_deref()
+                    .map_or(false, |entry| pattern.is_match(entry))
+        });
+
+        from_app
+            && matches(&self.summary, &notification.summary)
+            && matches(&self.body, &notification.body)
+            && self.category.as_deref().map_or(true, |category| {
+                notification.hints.get("category").and_then(Hint::as_str) == Some(category)
+            })
+            && self.urgency.map_or(true, |urgency| notification.urgency == urgency)
+            && self.hints.iter().all(|(key, expected)| {
+                notification
+                    .hints
+                    .get(key)
+                    .map_or(false, |hint| expected.matches(hint))
+            })
+    }
+
+    /// Changes the notification, returning `false` if it is dropped.
+    pub fn apply(&self, notification: &mut Notification) -> bool {
+        if self.drop {
+            return false;
+        }
+        if let Some(urgency) = self.set_urgency {
+            notification.set_urgency(urgency);
+        }
+        if let Some(rewrite) = &self.rewrite_summary {
+            notification.summary = rewrite
+                .pattern
+                .replace_all(&notification.summary, rewrite.replacement.as_str())
+                .into_owned();
+        }
+        if let Some(rewrite) = &self.rewrite_body {
+            notification.body = rewrite
+                .pattern
+                .replace_all(&notification.body, rewrite.replacement.as_str())
+                .into_owned();
+        }
+        if let Some(timeout) = self.expire_timeout {
+            notification.expire_timeout = timeout;
+        }
+        if let Some(transient) = self.transient {
+            notification.transient = transient;
+        }
+        notification.skip_history |= self.skip_history;
+        true
+    }
+
+    /// Returns the name of the rule for the logs.
+    fn label(&self, index: usize) -> String {
+        match &self.name {
+            Some(name) => format!("'{name}'"),
+            None => format!("#{}", index + 1),
+        }
+    }
+}
+
+/// Applies the rules in order, each seeing the changes of the previous ones.
+///
+/// Returns `None` if a rule drops the notification.
+pub fn apply(rules: &[Rule], mut notification: Notification) -> Option<Notification> {
+    for (index, rule) in rules.iter().enumerate() {
+        if rule.matches(&notification) {
+            log::debug!("rule {} matches notification {}", rule.label(index), notification.id);
+            if !rule.apply(&mut notification) {
+                return None;
+            }
+        }
+    }
+    Some(notification)
+}
+
+/// Reads a pattern, describing its syntax errors on a single line.
+fn regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+    let source = String::deserialize(deserializer)?;
+    Regex::new(&source).map_err(|e| {
+        let message = e.to_string();
+        let reason = message.lines().last().unwrap_or_default().trim_start_matches("error: ");
+        D::Error::custom(format!("invalid pattern '{source}': {reason}"))
+    })
+}
+
+fn optional_regex<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Regex>, D::Error> {
+    regex(deserializer).map(Some)
+}
+
+/// Reads an urgency written as `low`, `normal` or `critical`.
+fn urgency_by_name<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Urgency>, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    name.parse().map(Some).map_err(D::Error::custom)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(source: &str) -> Vec<Rule> {
+        #[derive(Deserialize)]
+        struct File {
+            rules: Vec<Rule>,
+        }
+        toml::from_str::<File>(source).expect("Parses rules").rules
+    }
+
+    fn notification() -> Notification {
+        Notification {
+            id: 1,
+            application: "ci-notifier".to_string(),
+            summary: "[CI] build passed".to_string(),
+            body: "pipeline <b>42</b>".to_string(),
+            hints: [
+                ("category".to_string(), Hint::String("build".to_string())),
+                ("x-pipeline".to_string(), Hint::UInt(42)),
+                ("x-muted".to_string(), Hint::Bool(false)),
+            ]
+            .into_iter()
+            .collect(),
+            expire_timeout: -1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn rules_conditions() {
+        let matching = |source: &str| rules(source)[0].matches(&notification());
+
+        assert!(matching("[[rules]]\n"), "no conditions");
+        assert!(matching("[[rules]]\napp_name = \"^ci-\"\nsummary = \"passed$\"\n"));
+        assert!(!matching("[[rules]]\napp_name = \"^ci-\"\nsummary = \"failed$\"\n"));
+        assert!(matching("[[rules]]\nbody = \"<b>\\\\d+</b>\"\n"), "body includes markup");
+        assert!(matching("[[rules]]\ncategory = \"build\"\nurgency = \"normal\"\n"));
+        assert!(!matching("[[rules]]\nurgency = \"critical\"\n"));
+        assert!(matching("[[rules]]\nhints = { x-pipeline = 42, x-muted = false }\n"));
+        assert!(!matching("[[rules]]\nhints = { x-pipeline = \"42\" }\n"), "types differ");
+        assert!(!matching("[[rules]]\nhints = { x-missing = true }\n"));
+    }
+
+    #[test]
+    fn rules_actions_in_order() {
+        let rules = rules(
+            "[[rules]]\n\
+             summary = \"passed\"\n\
+             set_urgency = \"low\"\n\
+             expire_timeout = 3000\n\
+             rewrite_summary = { pattern = \"^\\\\[CI\\\\] \" }\n\
+             skip_history = true\n\
+             \n\
+             [[rules]]\n\
+             summary = \"^\\\\[CI\\\\]\"\n\
+             drop = true\n\
+             \n\
+             [[rules]]\n\
+             urgency = \"low\"\n\
+             transient = true\n",
+        );
+
+        let notification = apply(&rules, notification()).expect("Keeps notification");
+        assert_eq!(notification.summary, "build passed");
+        assert_eq!(notification.urgency, Urgency::Low);
+        assert_eq!(notification.expire_timeout, 3000);
+        assert!(notification.skip_history);
+        assert!(notification.transient, "later rules see earlier changes");
+        assert_eq!(notification.hints.get("urgency"), Some(&Hint::Byte(0)), "hint follows urgency");
+
+        let failed = Notification {
+            summary: "[CI] build failed".to_string(),
+            ..self::notification()
+        };
+        assert!(apply(&rules, failed).is_none(), "dropped");
+    }
+
+    #[test]
+    fn rules_linear_matching() {
+        let long = Notification {
+            body: "x".repeat(100_000),
+            summary: format!("{}!", "a".repeat(24)),
+            ..notification()
+        };
+        assert!(rules("[[rules]]\nbody = \"^.*$\"\n")[0].matches(&long), "long body");
+        assert!(!rules("[[rules]]\nsummary = \"^(a+)+$\"\n")[0].matches(&long), "nested quantifier");
+    }
+
+    #[test]
+    fn rules_invalid() {
+        #[derive(Debug, Deserialize)]
+        struct File {
+            #[allow(dead_code)]
+            rules: Vec<Rule>,
+        }
+        let error = |source: &str| toml::from_str::<File>(source).expect_err("Rejects rules").to_string();
+
+        assert!(error("[[rules]]\nsummary = \"(unclosed\"\n").contains("invalid pattern '(unclosed': unclosed group"));
+        assert!(error("[[rules]]\nset_urgency = \"urgent\"\n").contains("unknown urgency 'urgent'"));
+        assert!(error("[[rules]]\ndelete = true\n").contains("unknown field"));
+    }
+}
@@ -0,0 +1,279 @@
+use crate::hint::Hint;
+use crate::notification::{Notification, NotificationStore};
+use crate::xdg;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{self, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+/// Version of the state file format, incremented on incompatible changes.
+pub const STATE_VERSION: u64 = 1;
+
+/// Content of the store and the id counter, as saved between runs.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct State {
+    /// version of the format
+    pub version: u64,
+    /// id the next notification gets
+    pub next_id: u32,
+    /// whether delivery is paused
+    pub paused: bool,
+    /// delivered notifications
+    pub notifications: Vec<Notification>,
+    /// removed notifications, the most recently removed last
+    pub history: Vec<Notification>,
+    /// notifications held back while delivery is paused
+    pub queue: Vec<Notification>,
+}
+
+impl State {
+    /// Captures the content of the store, leaving out the transient notifications.
+    ///
+    /// Hints holding a NaN or infinite number are left out too, as JSON cannot hold them.
+    pub fn capture(db: &NotificationStore, next_id: u32) -> Self {
+        let finite = |mut notification: Notification| {
+            notification
+                .hints
+                .retain(|_, hint| !matches!(hint, Hint::Double(value) if !value.is_finite()));
+            notification
+        };
+        let persistent = |notifications: Vec<Notification>| {
+            notifications.into_iter().filter(|n| !n.transient).map(finite).collect()
+        };
+        Self {
+            version: STATE_VERSION,
+            next_id,
+            paused: db.is_paused(),
+            notifications: persistent(db.items()),
+            history: db.history().into_iter().map(finite).collect(),
+            queue: persistent(db.queued()),
+        }
+    }
+
+    /// Returns the id the next notification should get, after all the known ones.
+    pub fn next_id(&self) -> u32 {
+        self.notifications
+            .iter()
+            .chain(&self.history)
+            .chain(&self.queue)
+            .map(|n| n.id.saturating_add(1))
+            .fold(self.next_id, u32::max)
+    }
+
+    /// Replaces the content of the store.
+    pub fn apply(self, db: &NotificationStore) {
+        db.load(self.notifications, self.history, self.queue, self.paused);
+    }
+
+    /// Upgrades the state saved by any version of the format.
+    fn migrate(value: Value) -> Result<Self, String> {
+        match value.get("version").and_then(Value::as_u64) {
+            Some(STATE_VERSION) => serde_json::from_value(value).map_err(|e| e.to_string()),
+            Some(version) => Err(format!("unsupported version {version}")),
+            None => Err("missing version".to_string()),
+        }
+    }
+}
+
+/// State file, written atomically so a crash never leaves a partial file behind.
+#[derive(Clone, Debug)]
+pub struct StateFile {
+    path: PathBuf,
+}
+
+impl StateFile {
+    /// Uses the state file at the given path.
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+
+    /// Returns the default path of the state file, in `$XDG_STATE_HOME`.
+    pub fn default_path() -> PathBuf {
+        xdg::state_home().join("armesto").join("state.json")
+    }
+
+    /// Reads the state saved by a previous run.
+    ///
+    /// A corrupt file or a file of an unsupported version is moved aside,
+    /// with a `.corrupt` suffix, and no state is returned.
+    pub fn load(&self) -> Option<State> {
+        let content = match fs::read(&self.path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!("unable to read state file {}: {}", self.path.display(), e);
+                return None;
+            }
+        };
+
+        let state = serde_json::from_slice(&content)
+            .map_err(|e| e.to_string())
+            .and_then(State::migrate);
+        match state {
+            Ok(state) => {
+                debug!("loaded state from {}", self.path.display());
+                Some(state)
+            }
+            Err(e) => {
+                let backup = self.sibling("corrupt");
+                warn!(
+                    "unable to load state file {}: {}, moving it to {}",
+                    self.path.display(),
+                    e,
+                    backup.display()
+                );
+                if let Err(e) = fs::rename(&self.path, &backup) {
+                    warn!("unable to move state file aside: {}", e);
+                }
+                None
+            }
+        }
+    }
+
+    /// Writes the state, only readable by the user.
+    ///
+    /// The state is written to a temporary file which then replaces the state file.
+    pub fn save(&self, state: &State) -> io::Result<()> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let content = serde_json::to_vec(state)?;
+        let temporary = self.sibling("tmp");
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temporary)?;
+        file.write_all(&content)?;
+        file.sync_all()?;
+        fs::rename(&temporary, &self.path)
+    }
+
+    /// Returns the path of the state file with an additional extension.
+    fn sibling(&self, extension: &str) -> PathBuf {
+        let mut name = self.path.file_name().unwrap_or_default().to_os_string();
+        name.push(".");
+        name.push(extension);
+        self.path.with_file_name(name)
+    }
+
+    /// Returns the path of the state file.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notification::Urgency;
+    use std::env;
+
+    #[test]
+    fn state_file_roundtrip() {
+        let dir = env::temp_dir().join(format!("armesto-state-test-{}", std::process::id()));
+        let unit = StateFile::new(dir.join("nested").join("state.json"));
+        assert!(unit.load().is_none(), "no state before the first save");
+
+        let db = NotificationStore::init();
+        db.add(Notification {
+            id: 3,
+            summary: "summary".to_string(),
+            urgency: Urgency::Critical,
+            hints: [("value".to_string(), Hint::Int(42))].into_iter().collect(),
+            ..Default::default()
+        });
+        db.add(Notification {
+            id: 7,
+            ..Default::default()
+        });
+        db.add(Notification {
+            id: 10,
+            transient: true,
+            ..Default::default()
+        });
+        db.delete(7);
+        db.set_paused(true);
+        db.enqueue(Notification {
+            id: 8,
+            ..Default::default()
+        });
+        unit.save(&State::capture(&db, 5)).expect("Saves state");
+        assert!(!unit.sibling("tmp").exists(), "temporary file is renamed");
+
+        let state = unit.load().expect("Loads state");
+        assert_eq!(state.version, STATE_VERSION);
+        assert_eq!(state.next_id(), 9, "ids continue after the known ones");
+
+        let restored = NotificationStore::init();
+        state.apply(&restored);
+        let notification = restored.get(3).expect("Restores notification");
+        assert_eq!(notification.summary, "summary");
+        assert_eq!(notification.urgency, Urgency::Critical);
+        assert_eq!(notification.hints.get("value"), Some(&Hint::Int(42)));
+        assert!(restored.get(10).is_none(), "transient notifications are not saved");
+        assert_eq!(restored.history().len(), 1);
+        assert_eq!(restored.queued_count(), 1);
+        assert!(restored.is_paused());
+
+        fs::remove_dir_all(dir).expect("Removes test directory");
+    }
+
+    #[test]
+    fn state_file_non_finite_hints() {
+        let dir = env::temp_dir().join(format!("armesto-state-non-finite-test-{}", std::process::id()));
+        let unit = StateFile::new(dir.join("state.json"));
+
+        let db = NotificationStore::init();
+        let hints = |value| {
+            [("value".to_string(), Hint::Double(value)), ("ratio".to_string(), Hint::Double(0.5))]
+                .into_iter()
+                .collect()
+        };
+        db.add(Notification {
+            id: 1,
+            hints: hints(f64::NAN),
+            ..Default::default()
+        });
+        db.add(Notification {
+            id: 2,
+            hints: hints(f64::INFINITY),
+            ..Default::default()
+        });
+        db.delete(2);
+        unit.save(&State::capture(&db, 3)).expect("Saves state");
+
+        let restored = NotificationStore::init();
+        unit.load().expect("Loads state").apply(&restored);
+        let notification = restored.get(1).expect("Restores notification");
+        assert_eq!(notification.hints.get("value"), None, "non-finite hints are dropped");
+        assert_eq!(notification.hints.get("ratio"), Some(&Hint::Double(0.5)));
+        assert_eq!(restored.history()[0].hints.len(), 1);
+
+        fs::remove_dir_all(dir).expect("Removes test directory");
+    }
+
+    #[test]
+    fn state_file_corrupt_or_unsupported() {
+        let dir = env::temp_dir().join(format!("armesto-state-corrupt-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).expect("Creates directory");
+        let unit = StateFile::new(dir.join("state.json"));
+
+        for content in ["{\"version\":1,\"notifications\":[", "{\"version\":99}", "[]"] {
+            fs::write(unit.path(), content).expect("Writes file");
+            assert!(unit.load().is_none(), "{content} is rejected");
+            assert!(!unit.path().exists(), "{content} is moved aside");
+            assert_eq!(fs::read_to_string(unit.sibling("corrupt")).ok().as_deref(), Some(content));
+        }
+
+        fs::write(unit.path(), "{\"version\":1,\"next_id\":4}").expect("Writes file");
+        assert_eq!(unit.load().map(|state| state.next_id()), Some(4), "missing fields default");
+
+        fs::remove_dir_all(dir).expect("Removes test directory");
+    }
+}
@@ -0,0 +1,335 @@
+use crate::error::{Error, Result};
+use crate::hint::Hint;
+use crate::notification::Notification;
+use std::collections::{BTreeMap, HashMap};
+use time::format_description::{self, OwnedFormatItem};
+use time::{OffsetDateTime, UtcOffset};
+
+/// Default template of the rendered notifications.
+pub const DEFAULT_TEMPLATE: &str = "{time} {app}: {summary}";
+
+/// Default format of the `{time}` placeholder.
+const DEFAULT_TIME_FORMAT: &str = "[hour]:[minute]";
+
+/// Time zone in which the times are rendered.
+#[derive(Clone, Copy, Debug)]
+pub enum TimeZone {
+    /// local time zone, following its daylight saving time changes
+    Local,
+    /// fixed offset from UTC
+    Fixed(UtcOffset),
+}
+
+impl TimeZone {
+    /// Returns the offset from UTC at the given time, in seconds since the epoch.
+    ///
+    /// The local offset is UTC if it is unknown.
+    fn offset_at(self, timestamp: i64) -> UtcOffset {
+        match self {
+            TimeZone::Local => local_offset_at(timestamp).unwrap_or(UtcOffset::UTC),
+            TimeZone::Fixed(offset) => offset,
+        }
+    }
+}
+
+/// Loads the local time zone for [`TimeZone::Local`].
+///
+/// # Safety
+///
+/// Must be called before any thread is started, as it writes the time zone state
+/// that the other threads read while rendering.
+pub unsafe fn load_local_time_zone() {
+    extern "C" {
+        // Not exported by the `libc` crate.
+        fn tzset();
+    }
+    tzset();
+}
+
+/// Returns the offset of the local time zone at the given time, in seconds since the epoch.
+fn local_offset_at(timestamp: i64) -> Option<UtcOffset> {
+    let time = libc::time_t::try_from(timestamp).ok()?;
+    let mut tm = std::mem::MaybeUninit::<libc::tm>::uninit();
+    // SAFETY: localtime_r only writes to the given struct, which is read if it succeeds.
+    // It also reads the `TZ` environment variable and the time zone state, which is why
+    // `time` refuses local offsets once threads run: a concurrent `setenv` or `tzset`
+    // would be a data race. The time zone is loaded by `load_local_time_zone` before any
+    // thread is started, and the process never changes its environment, which
+    // `clippy.toml` enforces by disallowing `std::env::set_var` and `remove_var`.
+    let tm = unsafe {
+        if libc::localtime_r(&time, tm.as_mut_ptr()).is_null() {
+            return None;
+        }
+        tm.assume_init()
+    };
+    UtcOffset::from_whole_seconds(i32::try_from(tm.tm_gmtoff).ok()?).ok()
+}
+
+/// Value inserted in place of a placeholder.
+#[derive(Clone, Debug)]
+enum Field {
+    Id,
+    App,
+    Application,
+    Summary,
+    Body,
+    BodyPango,
+    Urgency,
+    Time(OwnedFormatItem),
+    Age,
+    Hint(String),
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Text(String),
+    Field(Field),
+}
+
+/// Template rendering a notification as a single line of text.
+///
+/// Placeholders in braces are replaced by the values of the notification:
+///
+/// - `{id}`, `{summary}` and `{urgency}`
+/// - `{app}`, the human-friendly name of the app, and `{application}`, its raw name
+/// - `{body}`, the body as plain text, and `{body_pango}`, the body as Pango markup
+/// - `{time}`, the time the notification was received, as `[hour]:[minute]`
+///   by default or in the given [format](https://time-rs.github.io/book/api/format-description.html),
+///   e.g. `{time:[year]-[month]-[day] [hour]:[minute]}`
+/// - `{age}`, the time since the notification was received, e.g. `5m ago`
+/// - `{hint:<key>}`, the value of a hint, empty if it is not set
+///
+/// Braces are written as `{{` and `}}`. Line breaks in the values are replaced by spaces.
+#[derive(Clone, Debug)]
+pub struct Template {
+    parts: Vec<Part>,
+}
+
+impl Template {
+    /// Parses a template, describing the problem if it is invalid.
+    pub fn parse(source: &str) -> std::result::Result<Self, String> {
+        let mut parts = Vec::new();
+        let mut text = String::new();
+        let mut chars = source.char_indices().peekable();
+        while let Some((position, c)) = chars.next() {
+            match c {
+                '{' if chars.peek().map(|(_, c)| *c) == Some('{') => {
+                    chars.next();
+                    text.push('{');
+                }
+                '}' if chars.peek().map(|(_, c)| *c) == Some('}') => {
+                    chars.next();
+                    text.push('}');
+                }
+                '{' => {
+                    let placeholder: String = chars.by_ref().map(|(_, c)| c).take_while(|c| *c != '}').collect();
+                    if !source[position..].contains('}') {
+                        return Err(format!("unclosed '{{' at position {position}"));
+                    }
+                    let field = Self::field(&placeholder).map_err(|e| format!("{e} at position {position}"))?;
+                    if !text.is_empty() {
+                        parts.push(Part::Text(std::mem::take(&mut text)));
+                    }
+                    parts.push(Part::Field(field));
+                }
+                '}' => return Err(format!("unmatched '}}' at position {position}")),
+                c => text.push(c),
+            }
+        }
+        if !text.is_empty() {
+            parts.push(Part::Text(text));
+        }
+        Ok(Self { parts })
+    }
+
+    fn field(placeholder: &str) -> std::result::Result<Field, String> {
+        let (name, argument) = match placeholder.split_once(':') {
+            Some((name, argument)) => (name, Some(argument)),
+            None => (placeholder, None),
+        };
+        let field = match (name, argument) {
+            ("id", None) => Field::Id,
+            ("app", None) => Field::App,
+            ("application", None) => Field::Application,
+            ("summary", None) => Field::Summary,
+            ("body", None) => Field::Body,
+            ("body_pango", None) => Field::BodyPango,
+            ("urgency", None) => Field::Urgency,
+            ("age", None) => Field::Age,
+            ("time", format) => Field::Time(
+                format_description::parse_owned::<2>(format.unwrap_or(DEFAULT_TIME_FORMAT))
+                    .map_err(|e| format!("invalid time format '{}': {}", format.unwrap_or_default(), e))?,
+            ),
+            ("hint", Some(key)) if !key.is_empty() => Field::Hint(key.to_string()),
+            ("hint", _) => return Err("missing hint key".to_string()),
+            _ => return Err(format!("unknown placeholder '{{{placeholder}}}'")),
+        };
+        Ok(field)
+    }
+
+    /// Renders a notification at the time `now`, in seconds since the epoch.
+    pub fn render(&self, notification: &Notification, now: u64, zone: TimeZone) -> Result<String> {
+        let mut line = String::new();
+        for part in &self.parts {
+            let value = match part {
+                Part::Text(text) => text.clone(),
+                Part::Field(Field::Id) => notification.id.to_string(),
+                Part::Field(Field::App) => notification.app_name.clone(),
+                Part::Field(Field::Application) => notification.application.clone(),
+                Part::Field(Field::Summary) => notification.summary.clone(),
+                Part::Field(Field::Body) => notification.body_plain.clone(),
+                Part::Field(Field::BodyPango) => notification.body_pango.clone(),
+                Part::Field(Field::Urgency) => notification.urgency.to_string(),
+                Part::Field(Field::Time(format)) => i64::try_from(notification.timestamp)
+                    .ok()
+                    .and_then(|timestamp| {
+                        let time = OffsetDateTime::from_unix_timestamp(timestamp).ok()?;
+                        Some(time.to_offset(zone.offset_at(timestamp)))
+                    })
+                    .ok_or_else(|| Error::TemplateRender(format!("invalid timestamp {}", notification.timestamp)))?
+                    .format(format)
+                    .map_err(|e| Error::TemplateRender(e.to_string()))?,
+                Part::Field(Field::Age) => age(now.saturating_sub(notification.timestamp)),
+                Part::Field(Field::Hint(key)) => notification
+                    .hints
+                    .get(key)
+                    .map(Hint::to_string)
+                    .unwrap_or_default(),
+            };
+            line.extend(value.chars().map(|c| if c == '\n' || c == '\r' { ' ' } else { c }));
+        }
+        Ok(line)
+    }
+}
+
+/// Describes an elapsed time in seconds, e.g. `5m ago`.
+fn age(seconds: u64) -> String {
+    match seconds {
+        0..=59 => "now".to_string(),
+        60..=3599 => format!("{}m ago", seconds / 60),
+        3600..=86399 => format!("{}h ago", seconds / 3600),
+        _ => format!("{}d ago", seconds / 86400),
+    }
+}
+
+/// Default and named templates, rendering the times in a time zone.
+#[derive(Clone, Debug)]
+pub struct Templates {
+    default: Template,
+    templates: HashMap<String, Template>,
+    zone: TimeZone,
+}
+
+impl Templates {
+    /// Parses the default and the named templates.
+    pub fn new(default: &str, named: &BTreeMap<String, String>, zone: TimeZone) -> Result<Self> {
+        let parse = |name: &str, source: &str| {
+            Template::parse(source).map_err(|e| Error::TemplateParse(format!("{name}: {e}")))
+        };
+        let default = parse("default", default)?;
+        let templates = named
+            .iter()
+            .map(|(name, source)| Ok((name.clone(), parse(name, source)?)))
+            .collect::<Result<_>>()?;
+        Ok(Self { default, templates, zone })
+    }
+
+    /// Returns the template with the given name, or the default template.
+    pub fn get(&self, name: Option<&str>) -> Option<&Template> {
+        match name {
+            Some(name) => self.templates.get(name),
+            None => Some(&self.default),
+        }
+    }
+
+    /// Renders a notification with a template at the time `now`, in seconds since the epoch.
+    pub fn render(&self, template: &Template, notification: &Notification, now: u64) -> Result<String> {
+        template.render(notification, now, self.zone)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notification::Urgency;
+
+    fn notification() -> Notification {
+        Notification {
+            id: 7,
+            summary: "Build\nfailed".to_string(),
+            body_plain: "pipeline 42".to_string(),
+            body_pango: "pipeline <b>42</b>".to_string(),
+            application: "ci-notifier".to_string(),
+            app_name: "CI".to_string(),
+            urgency: Urgency::Critical,
+            hints: [("x-pipeline".to_string(), Hint::UInt(42))].into_iter().collect(),
+            // 2023-06-14 13:46:40 UTC
+            timestamp: 1_686_750_400,
+            ..Default::default()
+        }
+    }
+
+    fn render(source: &str) -> String {
+        Template::parse(source)
+            .expect("Parses template")
+            .render(&notification(), 1_686_750_400 + 7_200, TimeZone::Fixed(UtcOffset::UTC))
+            .expect("Renders template")
+    }
+
+    #[test]
+    fn template_render() {
+        assert_eq!(render(DEFAULT_TEMPLATE), "13:46 CI: Build failed", "line breaks are replaced");
+        assert_eq!(render("{id}|{application}|{urgency}|{age}"), "7|ci-notifier|critical|2h ago");
+        assert_eq!(render("{body} / {body_pango}"), "pipeline 42 / pipeline <b>42</b>");
+        assert_eq!(render("{time:[year]-[month]-[day]}"), "2023-06-14");
+        assert_eq!(render("#{hint:x-pipeline}{hint:x-missing}"), "#42");
+        assert_eq!(render("{{{id}}}"), "{7}", "escaped braces");
+
+        let offset = UtcOffset::from_hms(2, 0, 0).expect("Valid offset");
+        let local = Template::parse("{time}")
+            .expect("Parses template")
+            .render(&notification(), 0, TimeZone::Fixed(offset))
+            .expect("Renders template");
+        assert_eq!(local, "15:46", "local time");
+        assert!(local_offset_at(1_686_750_400).is_some(), "summer");
+        assert!(local_offset_at(1_702_648_000).is_some(), "winter");
+        assert_eq!(age(59), "now");
+        assert_eq!(age(3 * 86400), "3d ago");
+    }
+
+    #[test]
+    fn template_parse_errors() {
+        let error = |source: &str| Template::parse(source).err().unwrap_or_default();
+
+        assert_eq!(error("{app"), "unclosed '{' at position 0");
+        assert_eq!(error("app}"), "unmatched '}' at position 3");
+        assert_eq!(error("{app} {sender}"), "unknown placeholder '{sender}' at position 6");
+        assert_eq!(error("{hint}"), "missing hint key at position 0");
+        assert!(error("{time:[hours]}").starts_with("invalid time format '[hours]'"));
+    }
+
+    #[test]
+    fn templates_named() {
+        let named = [("short".to_string(), "{summary}".to_string())].into_iter().collect();
+        let unit = Templates::new("{id}", &named, TimeZone::Fixed(UtcOffset::UTC)).expect("Parses templates");
+
+        let short = unit.get(Some("short")).expect("Has named template");
+        assert_eq!(unit.render(short, &notification(), 0).ok().as_deref(), Some("Build failed"));
+        let default = unit.get(None).expect("Has default template");
+        assert_eq!(unit.render(default, &notification(), 0).ok().as_deref(), Some("7"));
+        assert!(unit.get(Some("missing")).is_none());
+
+        let shadowing = [("notification_message_template".to_string(), "{summary}".to_string())]
+            .into_iter()
+            .collect();
+        let unit = Templates::new("{id}", &shadowing, TimeZone::Fixed(UtcOffset::UTC)).expect("Parses templates");
+        let default = unit.get(None).expect("Has default template");
+        assert_eq!(unit.render(default, &notification(), 0).ok().as_deref(), Some("7"), "named apart");
+
+        let invalid = [("broken".to_string(), "{".to_string())].into_iter().collect();
+        assert!(matches!(
+            Templates::new("{id}", &invalid, TimeZone::Fixed(UtcOffset::UTC)),
+            Err(Error::TemplateParse(message)) if message == "broken: unclosed '{' at position 0"
+        ));
+    }
+}
@@ -0,0 +1,63 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+/// Returns the home directory of the user.
+pub fn home() -> Option<PathBuf> {
+    env::var_os("HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+}
+
+/// Returns the directory for user specific non-essential data, `$XDG_CACHE_HOME`.
+pub fn cache_home() -> PathBuf {
+    base_dir("XDG_CACHE_HOME", ".cache")
+}
+
+/// Returns the directory for user specific data files, `$XDG_DATA_HOME`.
+pub fn data_home() -> PathBuf {
+    base_dir("XDG_DATA_HOME", ".local/share")
+}
+
+/// Returns the directory for user specific configuration files, `$XDG_CONFIG_HOME`.
+pub fn config_home() -> PathBuf {
+    base_dir("XDG_CONFIG_HOME", ".config")
+}
+
+/// Returns the directory for user specific state files, `$XDG_STATE_HOME`.
+pub fn state_home() -> PathBuf {
+    base_dir("XDG_STATE_HOME", ".local/state")
+}
+
+/// Returns the directory for user specific runtime files such as sockets, `$XDG_RUNTIME_DIR`.
+///
+/// Unlike the other base directories, it has no fallback.
+pub fn runtime_dir() -> Option<PathBuf> {
+    env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+}
+
+/// Returns the directories to search for data files, `$XDG_DATA_HOME` followed by `$XDG_DATA_DIRS`.
+pub fn data_dirs() -> Vec<PathBuf> {
+    let dirs = env::var("XDG_DATA_DIRS")
+        .ok()
+        .filter(|dirs| !dirs.is_empty())
+        .unwrap_or_else(|| String::from("/usr/local/share:/usr/share"));
+
+    std::iter::once(data_home())
+        .chain(
+            dirs.split(':')
+                .filter(|dir| Path::new(dir).is_absolute())
+                .map(PathBuf::from),
+        )
+        .collect()
+}
+
+/// Reads an absolute path from the environment, falling back to a directory in the home directory.
+fn base_dir(var: &str, fallback: &str) -> PathBuf {
+    env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| home().map(|home| home.join(fallback)))
+        .unwrap_or_else(env::temp_dir)
+}